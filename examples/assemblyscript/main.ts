@@ -0,0 +1,21 @@
+// Minimal WASM-4 cart drawing a sprite and some text
+
+const DRAW_COLORS: usize = 0x14;
+
+const smiley = memory.data<u8>([0xc3, 0x81, 0x24, 0x24, 0x00, 0x24, 0x99, 0xc3]);
+
+// @ts-ignore: decorator
+@external("env", "textUtf16")
+declare function textUtf16(text: usize, byteLength: u32, x: i32, y: i32): void;
+
+// @ts-ignore: decorator
+@external("env", "blit")
+declare function blit(sprite: usize, x: i32, y: i32, width: u32, height: u32, flags: u32): void;
+
+export function update(): void {
+  const greeting = "Hello from AssemblyScript!";
+  store<u16>(DRAW_COLORS, 2);
+  textUtf16(changetype<usize>(greeting), String.UTF16.byteLength(greeting), 10, 10);
+  store<u16>(DRAW_COLORS, 0x40);
+  blit(smiley, 76, 76, 8, 8, 0);
+}
@@ -0,0 +1,23 @@
+// Minimal WASM-4 cart drawing a sprite and some text
+
+#include <stdint.h>
+
+#define WASM_IMPORT(name) __attribute__((import_module("env"), import_name(name)))
+#define WASM_EXPORT(name) __attribute__((export_name(name)))
+
+#define DRAW_COLORS ((volatile uint16_t*)0x14)
+
+WASM_IMPORT("text") void text(const char* text, int32_t x, int32_t y);
+WASM_IMPORT("blit")
+void blit(const uint8_t* sprite, int32_t x, int32_t y, uint32_t width, uint32_t height, uint32_t flags);
+
+static const uint8_t smiley[] = {
+    0xc3, 0x81, 0x24, 0x24, 0x00, 0x24, 0x99, 0xc3,
+};
+
+WASM_EXPORT("update") void update(void) {
+    *DRAW_COLORS = 2;
+    text("Hello from C!", 10, 10);
+    *DRAW_COLORS = 0x40;
+    blit(smiley, 76, 76, 8, 8, 0);
+}
@@ -0,0 +1,40 @@
+//! Minimal WASM-4 cart drawing a sprite and some text
+
+#![no_std]
+
+const DRAW_COLORS: *mut u16 = 0x14 as *mut u16;
+
+#[rustfmt::skip]
+static SMILEY: [u8; 8] = [
+    0b11000011,
+    0b10000001,
+    0b00100100,
+    0b00100100,
+    0b00000000,
+    0b00100100,
+    0b10011001,
+    0b11000011,
+];
+
+static GREETING: &str = "Hello from Rust!";
+
+extern "C" {
+    #[link_name = "textUtf8"]
+    fn text_utf8(text: *const u8, byte_len: usize, x: i32, y: i32);
+    fn blit(sprite: *const u8, x: i32, y: i32, width: u32, height: u32, flags: u32);
+}
+
+#[no_mangle]
+extern "C" fn update() {
+    unsafe {
+        *DRAW_COLORS = 2;
+        text_utf8(GREETING.as_ptr(), GREETING.len(), 10, 10);
+        *DRAW_COLORS = 0x40;
+        blit(SMILEY.as_ptr(), 76, 76, 8, 8, 0);
+    }
+}
+
+#[panic_handler]
+fn panic(_: &core::panic::PanicInfo) -> ! {
+    core::arch::wasm32::unreachable()
+}
@@ -0,0 +1,16 @@
+//! Minimal WASM-4 cart drawing a sprite and some text
+
+const draw_colors: *volatile u16 = @ptrFromInt(0x14);
+
+const smiley = [8]u8{ 0xc3, 0x81, 0x24, 0x24, 0x00, 0x24, 0x99, 0xc3 };
+
+extern fn textUtf8(text: [*]const u8, byte_len: usize, x: i32, y: i32) void;
+extern fn blit(sprite: [*]const u8, x: i32, y: i32, width: u32, height: u32, flags: u32) void;
+
+export fn update() void {
+    const greeting = "Hello from Zig!";
+    draw_colors.* = 2;
+    textUtf8(greeting, greeting.len, 10, 10);
+    draw_colors.* = 0x40;
+    blit(&smiley, 76, 76, 8, 8, 0);
+}
@@ -0,0 +1,73 @@
+//! Running the unpacker under an interpreter to estimate decompression cost
+
+use std::time::Instant;
+
+use anyhow::Context;
+
+use crate::{PackedData, UnpackBench, COMPRESSED_DATA_OFFSET, CONTEXT_OFFSET, DEFAULT_MEM_SIZE};
+
+/// Decompress every cluster of the `packed` data with the unpacker, summing up its cost
+pub(crate) fn bench_unpack(
+    packed: &PackedData,
+    unpacker_wasm: &[u8],
+) -> anyhow::Result<UnpackBench> {
+    let mut config = wasmi::Config::default();
+    config.consume_fuel(true);
+    let engine = wasmi::Engine::new(&config);
+    let module = wasmi::Module::new(&engine, unpacker_wasm).context("loading the unpacker")?;
+    let mut store = wasmi::Store::new(&engine, ());
+    // The unpacker's memory is limited to a single page
+    let memory = wasmi::Memory::new(&mut store, wasmi::MemoryType::new(1, Some(1))?)?;
+    let mut linker = wasmi::Linker::new(&engine);
+    linker.define("env", "memory", memory)?;
+    let instance = linker
+        .instantiate(&mut store, &module)?
+        .ensure_no_start(&mut store)?;
+    // The unpacker exports nothing but the unpack function
+    let unpack_name = module
+        .exports()
+        .next()
+        .context("unpacker has no exports")?
+        .name();
+    let unpack = instance.get_typed_func::<(i32, i32, i32), i32>(&store, unpack_name)?;
+
+    let mut bench = UnpackBench {
+        fuel: 0,
+        host_time: Default::default(),
+    };
+    for (compressed, data_len) in clusters(packed) {
+        // Compressed data and the staging area are placed like in the default layout
+        let staging = DEFAULT_MEM_SIZE - data_len;
+        anyhow::ensure!(
+            COMPRESSED_DATA_OFFSET + compressed.len() as i32 <= staging,
+            "cluster does not fit into a single page for benchmarking"
+        );
+        memory.data_mut(&mut store).fill(0);
+        memory.write(&mut store, COMPRESSED_DATA_OFFSET as usize, compressed)?;
+
+        store.set_fuel(u64::MAX)?;
+        let start = Instant::now();
+        unpack
+            .call(
+                &mut store,
+                (CONTEXT_OFFSET, staging, COMPRESSED_DATA_OFFSET),
+            )
+            .context("running the unpacker")?;
+        bench.host_time += start.elapsed();
+        bench.fuel += u64::MAX - store.get_fuel()?;
+    }
+    Ok(bench)
+}
+
+/// Compressed data of each cluster with the length of its staging area
+fn clusters(packed: &PackedData) -> impl Iterator<Item = (&[u8], i32)> + '_ {
+    let start = packed.layout.compressed_start();
+    packed.layout.clusters.iter().map(move |cluster| {
+        let compressed =
+            (cluster.compressed.start - start) as usize..(cluster.compressed.end - start) as usize;
+        (
+            &packed.data[compressed],
+            cluster.staging.end - cluster.staging.start,
+        )
+    })
+}
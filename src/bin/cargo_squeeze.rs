@@ -0,0 +1,109 @@
+//! `cargo squeeze`, which builds the crate for `wasm32-unknown-unknown` and squeezes every
+//! built module next to it
+
+use std::{
+    env,
+    path::{Path, PathBuf},
+    process,
+};
+
+use anyhow::Context;
+use clap::Parser;
+
+/// Suffix of the squeezed modules, which are skipped when looking for built ones
+const SUFFIX: &str = "-squeezed.wasm";
+
+#[derive(Parser)]
+#[command(bin_name = "cargo")]
+enum Cargo {
+    /// Build the crate for `wasm32-unknown-unknown` in release mode and squeeze every built
+    /// module into `<name>-squeezed.wasm` next to it
+    #[command(after_long_help = "Example:\n  cargo squeeze -- --target microw8")]
+    Squeeze {
+        /// Squeeze modules which are already built instead of running `cargo build`.
+        #[clap(long)]
+        no_build: bool,
+        /// Options passed to `wasm-squeeze`.
+        #[clap(last = true)]
+        wasm_squeeze_args: Vec<String>,
+    },
+}
+
+fn main() -> process::ExitCode {
+    match try_main() {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("error: {err:?}");
+            process::ExitCode::FAILURE
+        }
+    }
+}
+
+fn try_main() -> anyhow::Result<process::ExitCode> {
+    let Cargo::Squeeze {
+        no_build,
+        wasm_squeeze_args,
+    } = Cargo::parse();
+    let cargo = env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    if !no_build {
+        let status = process::Command::new(&cargo)
+            .args(["build", "--release", "--target", "wasm32-unknown-unknown"])
+            .status()
+            .context("running `cargo build`")?;
+        anyhow::ensure!(status.success(), "`cargo build` failed with {status}");
+    }
+
+    let release_dir = target_dir(&cargo)?.join("wasm32-unknown-unknown/release");
+    let mut modules = Vec::new();
+    let entries = std::fs::read_dir(&release_dir)
+        .with_context(|| format!("reading {}", release_dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        if name.ends_with(".wasm") && !name.ends_with(SUFFIX) {
+            modules.push(path);
+        }
+    }
+    anyhow::ensure!(
+        !modules.is_empty(),
+        "no wasm modules are built in {}",
+        release_dir.display()
+    );
+
+    // `wasm-squeeze` is installed along with this binary
+    let wasm_squeeze = env::current_exe()?.with_file_name("wasm-squeeze");
+    let status = process::Command::new(&wasm_squeeze)
+        // Separate values starting with `-` would be taken for flags
+        .arg(format!("--suffix={SUFFIX}"))
+        .args(&wasm_squeeze_args)
+        .arg("--")
+        .args(&modules)
+        .status()
+        .with_context(|| format!("running {}", wasm_squeeze.display()))?;
+    Ok(match status.code() {
+        Some(code) => process::ExitCode::from(code as u8),
+        None => process::ExitCode::FAILURE,
+    })
+}
+
+/// Target directory of the current workspace, which `CARGO_TARGET_DIR` overrides
+fn target_dir(cargo: &std::ffi::OsStr) -> anyhow::Result<PathBuf> {
+    if let Some(dir) = env::var_os("CARGO_TARGET_DIR") {
+        return Ok(dir.into());
+    }
+    let output = process::Command::new(cargo)
+        .args(["locate-project", "--workspace", "--message-format=plain"])
+        .stderr(process::Stdio::inherit())
+        .output()
+        .context("running `cargo locate-project`")?;
+    anyhow::ensure!(
+        output.status.success(),
+        "`cargo locate-project` failed with {}",
+        output.status
+    );
+    let manifest = String::from_utf8(output.stdout)?;
+    let workspace_root = Path::new(manifest.trim())
+        .parent()
+        .context("workspace manifest has no parent directory")?;
+    Ok(workspace_root.join("target"))
+}
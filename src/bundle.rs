@@ -0,0 +1,70 @@
+//! Carts bundled into HTML pages by `w4 bundle --html`
+//!
+//! The page embeds the cart as base64 in a JSON object with the `WASM4_CART` and
+//! `WASM4_CART_SIZE` fields.
+
+use std::ops::Range;
+
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+const CART_KEY: &str = "\"WASM4_CART\"";
+const CART_SIZE_KEY: &str = "\"WASM4_CART_SIZE\"";
+
+/// Extract the cart's wasm module from the bundled `html` page
+pub fn extract_html_cart(html: &str) -> anyhow::Result<Vec<u8>> {
+    let cart = &html[cart_range(html)?];
+    STANDARD.decode(cart).context("decoding the embedded cart")
+}
+
+/// Replace the cart embedded into the bundled `html` page with the `cart` module
+pub fn replace_html_cart(html: &str, cart: &[u8]) -> anyhow::Result<String> {
+    let cart_range = cart_range(html)?;
+    let size_range = value_range(html, CART_SIZE_KEY, |value| {
+        value
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(value.len())
+    })?;
+
+    // Replace the latter value first to keep the former's range valid
+    let mut ranges = [
+        (cart_range, STANDARD.encode(cart)),
+        (size_range, cart.len().to_string()),
+    ];
+    ranges.sort_by_key(|(range, _)| std::cmp::Reverse(range.start));
+    let mut html = html.to_owned();
+    for (range, value) in ranges {
+        html.replace_range(range, &value);
+    }
+    Ok(html)
+}
+
+/// Range of the embedded base64 cart, without quotes
+fn cart_range(html: &str) -> anyhow::Result<Range<usize>> {
+    let quoted = value_range(html, CART_KEY, |value| {
+        let string_len = value.strip_prefix('"').and_then(|rest| rest.find('"'));
+        string_len.map_or(0, |len| len + 2)
+    })?;
+    anyhow::ensure!(!quoted.is_empty(), "embedded cart is not a string");
+    Ok(quoted.start + 1..quoted.end - 1)
+}
+
+/// Range of the value following the JSON `key`, which ends where `value_len` says
+fn value_range(
+    html: &str,
+    key: &str,
+    value_len: impl FnOnce(&str) -> usize,
+) -> anyhow::Result<Range<usize>> {
+    let key_end = html
+        .find(key)
+        .map(|start| start + key.len())
+        .with_context(|| format!("no {key} found, the input is not a bundled cart"))?;
+    let after_key = &html[key_end..];
+    let value = after_key
+        .trim_start()
+        .strip_prefix(':')
+        .with_context(|| format!("{key} is not followed by a value"))?
+        .trim_start();
+    let start = html.len() - value.len();
+    Ok(start..start + value_len(value))
+}
@@ -0,0 +1,70 @@
+//! Components, which are squeezed core module by core module
+
+use anyhow::Context;
+use wasm_encoder as we;
+use wasmparser as wp;
+
+use crate::{squeeze, SqueezeOptions};
+
+/// Whether the `header` of the input belongs to a component rather than a core module
+pub(crate) fn is_component(header: &[u8]) -> bool {
+    // Layer field follows the version
+    header.starts_with(b"\0asm") && header.get(6..8) == Some(&[1, 0])
+}
+
+/// Squeeze every core module of the `input` component, including the ones of nested components,
+/// keeping other sections as they are
+pub(crate) fn squeeze_component(input: &[u8], options: &SqueezeOptions) -> anyhow::Result<Vec<u8>> {
+    let mut component = we::Component::new();
+    let mut parser = wp::Parser::new(0);
+    let mut offset = 0;
+    loop {
+        let wp::Chunk::Parsed { consumed, payload } = parser.parse(&input[offset..], true)? else {
+            unreachable!("the whole component is available");
+        };
+        offset += consumed;
+        match payload {
+            wp::Payload::Version {
+                encoding: wp::Encoding::Component,
+                ..
+            } => (),
+            wp::Payload::Version { .. } => anyhow::bail!("input is not a component"),
+            wp::Payload::ModuleSection {
+                unchecked_range: range,
+                ..
+            } => {
+                let module = squeeze(&input[range.clone()], options)
+                    .with_context(|| format!("squeezing the core module at {:#x}", range.start))?;
+                component.section(&we::RawSection {
+                    id: we::ComponentSectionId::CoreModule.into(),
+                    data: &module,
+                });
+                // Nested payloads are skipped
+                offset = range.end;
+            }
+            wp::Payload::ComponentSection {
+                unchecked_range: range,
+                ..
+            } => {
+                let nested = squeeze_component(&input[range.clone()], options)
+                    .with_context(|| format!("squeezing the component at {:#x}", range.start))?;
+                component.section(&we::RawSection {
+                    id: we::ComponentSectionId::Component.into(),
+                    data: &nested,
+                });
+                offset = range.end;
+            }
+            wp::Payload::End(_) => break,
+            payload => {
+                let (id, range) = payload
+                    .as_section()
+                    .context("unexpected payload in a component")?;
+                component.section(&we::RawSection {
+                    id,
+                    data: &input[range],
+                });
+            }
+        }
+    }
+    Ok(component.finish())
+}
@@ -0,0 +1,119 @@
+//! Native implementation of the unpack function for embedders importing it with
+//! `--unpacker-as-import`
+//!
+//! The imported function has the `(context: i32, destination: i32, compressed: i32) -> i32`
+//! signature. It decompresses data starting at the `compressed` address into the memory at the
+//! `destination` address and returns the decompressed length. The `context` area is scratch
+//! space of the embedded unpacker, which the native implementation does not need. Data has to be
+//! unpacked with the same [`PackerConfig`] it was squeezed with.
+//!
+//! For example with wasmtime:
+//!
+//! ```ignore
+//! linker.func_wrap("env", "upkr_unpack", |mut caller: Caller<'_, ()>, context, destination, compressed| {
+//!     let memory = caller.get_export("memory").unwrap().into_memory().unwrap();
+//!     let memory = memory.data_mut(&mut caller);
+//!     wasm_squeeze::host::unpack(memory, packer, context, destination, compressed)
+//! })?;
+//! ```
+
+use anyhow::Context;
+
+use crate::PackerConfig;
+
+/// Decompress data at the `compressed` address of the `memory` into the `destination` address,
+/// returning the decompressed length. The `config` has to match `SqueezeOptions::packer`.
+pub fn unpack(
+    memory: &mut [u8],
+    config: PackerConfig,
+    _context: u32,
+    destination: u32,
+    compressed: u32,
+) -> anyhow::Result<u32> {
+    let destination = usize::try_from(destination)?;
+    let compressed = usize::try_from(compressed)?;
+    let max_size = memory
+        .len()
+        .checked_sub(destination)
+        .context("destination is out of memory bounds")?;
+    let packed = memory
+        .get(compressed..)
+        .context("compressed data is out of memory bounds")?;
+    let data = upkr::unpack(packed, &config.upkr(), max_size).context("decompressing data")?;
+    memory[destination..][..data.len()].copy_from_slice(&data);
+    Ok(data.len().try_into()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{squeeze_slice, SqueezeOptions};
+
+    /// Memory exported by the `module` after its start function, with the unpack function
+    /// implemented natively
+    fn memory_image(module: &[u8], packer: PackerConfig) -> Vec<u8> {
+        let engine = wasmi::Engine::default();
+        let module = wasmi::Module::new(&engine, module).unwrap();
+        let mut store = wasmi::Store::new(&engine, ());
+        let mut linker = wasmi::Linker::new(&engine);
+        linker
+            .func_wrap(
+                "env",
+                "upkr_unpack",
+                move |mut caller: wasmi::Caller<'_, ()>,
+                      context: i32,
+                      destination: i32,
+                      compressed: i32| {
+                    let memory = caller.get_export("memory").unwrap().into_memory().unwrap();
+                    let memory = memory.data_mut(&mut caller);
+                    let len = unpack(
+                        memory,
+                        packer,
+                        context as u32,
+                        destination as u32,
+                        compressed as u32,
+                    )
+                    .unwrap();
+                    len as i32
+                },
+            )
+            .unwrap();
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .unwrap()
+            .start(&mut store)
+            .unwrap();
+        let memory = instance.get_memory(&store, "memory").unwrap();
+        memory.data(&store).to_vec()
+    }
+
+    #[test]
+    fn imported_unpacker_restores_memory() {
+        let data = "wasm-squeeze ".repeat(0x100);
+        let input = wat::parse_str(format!(
+            r#"(module
+                (memory (export "memory") 1)
+                (func $start)
+                (start $start)
+                (data (i32.const 0x20) "{data}")
+                (data (i32.const 0x4000) "{data}"))"#
+        ))
+        .unwrap();
+        let packer = PackerConfig {
+            parity_contexts: 2,
+            bitstream: true,
+            ..PackerConfig::default()
+        };
+        let options = SqueezeOptions {
+            unpacker_import: Some(("env".to_owned(), "upkr_unpack".to_owned())),
+            packer,
+            ..SqueezeOptions::default()
+        };
+        let outcome = squeeze_slice(&input, &options).unwrap();
+        assert_eq!(outcome.passthrough_reason, None);
+        assert_eq!(
+            memory_image(&outcome.output, packer),
+            memory_image(&input, packer)
+        );
+    }
+}
@@ -0,0 +1,3629 @@
+//! UPX-like tool to compress [WASM-4](https://wasm4.org/) cartridges and embed the decompressor
+//! into the binary for it to decompress itself.
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    error::Error,
+    fmt,
+    io::{self, Read},
+    iter,
+    ops::Range,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use wasm_encoder::{
+    self as we,
+    reencode::{self, Reencode},
+    Encode,
+};
+use wasmparser::{self as wp, FromReader};
+
+pub use target::{MemoryRegion, RegisterInit, RegisterValue, Target, TargetProfile};
+pub use unsqueeze::unsqueeze;
+
+#[cfg(feature = "bench")]
+mod bench;
+#[cfg(feature = "bundle")]
+pub mod bundle;
+mod component;
+#[cfg(feature = "host-upkr")]
+pub mod host;
+pub mod stream;
+mod target;
+mod unsqueeze;
+#[cfg(feature = "verify-exec")]
+pub mod verify;
+
+/// Supported wasm features
+pub const WASM_FEATURES: wp::WasmFeatures = {
+    use wp::WasmFeatures as Ft;
+
+    Ft::BULK_MEMORY
+        .union(Ft::EXCEPTIONS)
+        .union(Ft::EXTENDED_CONST)
+        .union(Ft::FLOATS)
+        .union(Ft::FUNCTION_REFERENCES)
+        .union(Ft::GC)
+        .union(Ft::LEGACY_EXCEPTIONS)
+        .union(Ft::MULTI_VALUE)
+        .union(Ft::MUTABLE_GLOBAL)
+        .union(Ft::REFERENCE_TYPES)
+        .union(Ft::RELAXED_SIMD)
+        .union(Ft::SATURATING_FLOAT_TO_INT)
+        .union(Ft::SIGN_EXTENSION)
+        .union(Ft::SIMD)
+        .union(Ft::TAIL_CALL)
+};
+/// Features which may be disabled for the target runtime, named after their clang flags
+pub const OPTIONAL_FEATURES: &[(&str, wp::WasmFeatures)] = {
+    use wp::WasmFeatures as Ft;
+
+    &[
+        ("bulk-memory", Ft::BULK_MEMORY),
+        ("multivalue", Ft::MULTI_VALUE),
+        ("mutable-globals", Ft::MUTABLE_GLOBAL),
+        ("nontrapping-fptoint", Ft::SATURATING_FLOAT_TO_INT),
+        ("reference-types", Ft::REFERENCE_TYPES),
+        ("sign-ext", Ft::SIGN_EXTENSION),
+        ("simd128", Ft::SIMD),
+    ]
+};
+const UNPACKER_WASM: &[u8] = include_bytes!("upkr_unpacker.wasm");
+
+/// Memory size assumed if the module declares no memory
+const DEFAULT_MEM_SIZE: i32 = 0x10000;
+const PAGE_SIZE: u64 = 0x10000;
+const CONTEXT_OFFSET: i32 = 0;
+const COMPRESSED_DATA_OFFSET: i32 = common::CONTEXT_SIZE;
+
+/// Options for [`squeeze`]
+#[derive(Clone)]
+pub struct SqueezeOptions {
+    /// The compression level (0-9)
+    pub level: u8,
+    /// Place the unpacker context inside of the original data region, which is overwritten with
+    /// decompressed data last, instead of the low memory
+    pub context_in_data: bool,
+    /// Do not compress data, only merge data segments
+    pub no_compress: bool,
+    /// Do not inject the unpacker, simply reencode the input module
+    pub no_inject: bool,
+    /// Report zero gaps between data segments of at least this many bytes
+    pub report_gaps: usize,
+    /// Import and export renames
+    pub renames: Renames,
+    /// Runtime which state the prologue restores
+    pub target: Target,
+    /// Pass through the input module instead of failing on internal errors
+    pub keep_going: bool,
+    /// Compress clusters of data separated by zero gaps of at least this many bytes separately,
+    /// if that comes out smaller. Takes precedence over other layout options.
+    pub split_gaps: Option<usize>,
+    /// Do not merge data segments separated by gaps larger than this many bytes, compressing
+    /// them separately instead
+    pub max_gap: Option<usize>,
+    /// Features the target runtime lacks, which injected code must not use
+    pub disabled_features: wp::WasmFeatures,
+    /// Run the unpacker on the compressed data under an interpreter and report its cost, which
+    /// requires the `bench` feature
+    pub bench_unpack: bool,
+    /// Move non-zero initializers of mutable globals into the compressed data, restoring them in
+    /// the prologue. Only used with the default layout.
+    pub compress_globals: bool,
+    /// Import the unpack function from the given module and field instead of injecting the
+    /// unpacker's code, for runtimes providing it natively
+    pub unpacker_import: Option<(String, String)>,
+    /// Decompress data right into its place instead of copying it from the top of the memory
+    pub in_place: bool,
+    /// If compression does not pay off, still emit merged and trimmed data without the unpacker
+    pub merge_fallback: bool,
+    /// Compressed format, which only the imported unpack function may support if it differs
+    /// from the default one
+    pub packer: PackerConfig,
+    /// Inject this unpacker instead of the embedded one, compressing data with its packer
+    pub custom_unpacker: Option<CustomUnpacker>,
+    /// Memory ranges the injected code must neither use nor zero
+    pub reserved: Vec<Range<i32>>,
+    /// Drop all custom sections from the output, including names
+    pub strip: bool,
+    /// Drop custom sections with these names from the output
+    pub strip_custom: Vec<String>,
+    /// Unsqueeze an already squeezed input and squeeze it again instead of passing it through
+    pub resqueeze: bool,
+    /// Squeeze modules of unknown binary versions as version 1 with a warning instead of failing,
+    /// keeping their version in the output
+    pub best_effort: bool,
+    /// Decompress packed data in-process and fail unless it matches the original data
+    pub assert_roundtrip: bool,
+    /// Run start functions of the input and the squeezed modules under an interpreter with stub
+    /// imports, failing unless they leave the same memory. Requires the `verify-exec` feature.
+    pub verify_exec: bool,
+}
+
+/// Unpacker injected instead of the embedded one
+#[derive(Clone)]
+pub struct CustomUnpacker {
+    /// Wasm module which may only import `env.memory` and has to export a single unpack function
+    /// `(context, destination, compressed) -> end` of `i32` values. The function may use
+    /// [`common::CONTEXT_SIZE`] bytes at `context` and must not rely on globals or tables.
+    pub wasm: Vec<u8>,
+    /// Compressor of data for the unpacker
+    pub packer: Arc<dyn Packer>,
+}
+
+/// Compressor of data for a custom unpacker, shared between threads
+pub trait Packer: Send + Sync {
+    /// Compress `data` with the compression `level` ranging from 0 to 9
+    fn pack(&self, data: &[u8], level: u8) -> anyhow::Result<Vec<u8>>;
+}
+
+/// Tunables of the compressed format, a subset of `upkr::Config`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PackerConfig {
+    /// Number of contexts selected by the low bits of the position, a power of two
+    pub parity_contexts: usize,
+    pub invert_bit_encoding: bool,
+    pub simplified_prob_update: bool,
+    /// Encode into a bitstream instead of a bytestream
+    pub bitstream: bool,
+}
+
+impl Default for PackerConfig {
+    fn default() -> Self {
+        PackerConfig {
+            parity_contexts: 1,
+            invert_bit_encoding: false,
+            simplified_prob_update: false,
+            bitstream: false,
+        }
+    }
+}
+
+impl PackerConfig {
+    fn upkr(self) -> upkr::Config {
+        upkr::Config {
+            parity_contexts: self.parity_contexts,
+            invert_bit_encoding: self.invert_bit_encoding,
+            simplified_prob_update: self.simplified_prob_update,
+            use_bitstream: self.bitstream,
+            ..upkr::Config::default()
+        }
+    }
+}
+
+impl Default for SqueezeOptions {
+    fn default() -> Self {
+        SqueezeOptions {
+            level: 9,
+            context_in_data: false,
+            no_compress: false,
+            no_inject: false,
+            report_gaps: 1024,
+            renames: Renames::default(),
+            target: Target::default(),
+            keep_going: false,
+            split_gaps: None,
+            max_gap: None,
+            disabled_features: wp::WasmFeatures::empty(),
+            bench_unpack: false,
+            compress_globals: false,
+            unpacker_import: None,
+            in_place: false,
+            merge_fallback: false,
+            packer: PackerConfig::default(),
+            custom_unpacker: None,
+            reserved: Vec::new(),
+            strip: false,
+            strip_custom: Vec::new(),
+            resqueeze: false,
+            best_effort: false,
+            assert_roundtrip: false,
+            verify_exec: false,
+        }
+    }
+}
+
+impl SqueezeOptions {
+    /// Compress `data` for the injected or imported unpacker
+    fn pack(&self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+        match &self.custom_unpacker {
+            Some(custom) => custom
+                .packer
+                .pack(data, self.level)
+                .context("custom packer failed"),
+            None => {
+                let config = self.packer.upkr();
+                let packed = upkr::pack(data, self.level, &config, None);
+                if self.assert_roundtrip {
+                    let unpacked = upkr::unpack(&packed, &config, data.len())
+                        .context("decompressing packed data back")?;
+                    if unpacked != data {
+                        let offset = iter::zip(&unpacked, data)
+                            .position(|(unpacked, original)| unpacked != original)
+                            .unwrap_or(unpacked.len().min(data.len()));
+                        anyhow::bail!(
+                            "packed data decompresses into {} bytes differing from the original \
+                             {} bytes at offset {offset:#x}",
+                            unpacked.len(),
+                            data.len()
+                        );
+                    }
+                }
+                Ok(packed)
+            }
+        }
+    }
+
+    /// Whether the custom section `name` is dropped from the output
+    fn strips(&self, name: &str) -> bool {
+        self.strip || self.strip_custom.iter().any(|stripped| stripped == name)
+    }
+
+    /// Module of the unpacker to inject, if it is not imported
+    fn unpacker_wasm(&self) -> Option<&[u8]> {
+        match (&self.unpacker_import, &self.custom_unpacker) {
+            (Some(_), _) => None,
+            (None, Some(custom)) => Some(&custom.wasm),
+            (None, None) => Some(UNPACKER_WASM),
+        }
+    }
+
+    /// Memory ranges reserved by the options and by the target runtime
+    fn reserved_ranges(&self) -> Vec<Range<i32>> {
+        let target = self.target.profile().reserved;
+        self.reserved.iter().chain(target).cloned().collect()
+    }
+}
+
+/// Squeeze the wasm module, returning the input back if squeezing was not beneficial
+pub fn squeeze(input: &[u8], options: &SqueezeOptions) -> anyhow::Result<Vec<u8>> {
+    squeeze_slice(input, options).map(|outcome| outcome.output)
+}
+
+/// Squeeze the wasm module parsing it right in the `input` slice, like a memory-mapped file,
+/// instead of copying it chunk by chunk
+pub fn squeeze_slice(input: &[u8], options: &SqueezeOptions) -> anyhow::Result<SqueezeOutcome> {
+    // Components and other binary versions are handled by the reader
+    if input.get(4..8) != Some(&[1, 0, 0, 0]) {
+        return squeeze_reader(input, options);
+    }
+    let mut info = RelevantInfoBuilder::new();
+    let mut parser = wp::Parser::new(0);
+    parser.set_features(WASM_FEATURES);
+    for payload in parser.parse_all(input) {
+        info.add_payload(payload.context("parsing input as wasm module")?)?;
+    }
+    squeeze_core(Cow::Borrowed(input), info, options)
+}
+
+/// Validate the squeezed `output` module, pointing out the function with the invalid code
+pub fn validate_output(output: &[u8]) -> anyhow::Result<()> {
+    if component::is_component(output) {
+        wp::Validator::new_with_features(WASM_FEATURES | wp::WasmFeatures::COMPONENT_MODEL)
+            .validate_all(output)
+            .context("squeezed component is invalid")?;
+        return Ok(());
+    }
+    let Err(err) = wp::Validator::new_with_features(WASM_FEATURES).validate_all(output) else {
+        return Ok(());
+    };
+    let offset = err.offset();
+    let mut function = None;
+    let mut names = HashMap::new();
+    let mut import_function_count = 0;
+    let mut defined_function_count = 0;
+    for payload in wp::Parser::new(0).parse_all(output) {
+        match payload? {
+            wp::Payload::ImportSection(imports) => {
+                for import in imports {
+                    if let wp::TypeRef::Func(_) = import?.ty {
+                        import_function_count += 1;
+                    }
+                }
+            }
+            wp::Payload::CodeSectionEntry(body) => {
+                if body.range().contains(&offset) {
+                    function = Some(import_function_count + defined_function_count);
+                }
+                defined_function_count += 1;
+            }
+            wp::Payload::CustomSection(section) => {
+                if let wp::KnownCustom::Name(reader) = section.as_known() {
+                    for subsection in reader {
+                        if let wp::Name::Function(map) = subsection? {
+                            for naming in map {
+                                let naming = naming?;
+                                names.insert(naming.index, naming.name);
+                            }
+                        }
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    let location = match function {
+        Some(idx) => match names.get(&idx) {
+            Some(name) => format!("function {idx} `{name}`"),
+            None => format!("function {idx}"),
+        },
+        None => "outside of function bodies".to_owned(),
+    };
+    Err(anyhow::Error::from(err).context(format!(
+        "squeezed module is invalid at offset {offset:#x}, {location}"
+    )))
+}
+
+/// Read the wasm module from the `reader` and squeeze it. Components are squeezed core module by
+/// core module, reporting only the sizes.
+pub fn squeeze_reader<R: io::Read>(
+    mut reader: R,
+    options: &SqueezeOptions,
+) -> anyhow::Result<SqueezeOutcome> {
+    let mut header = Vec::new();
+    reader
+        .by_ref()
+        .take(8)
+        .read_to_end(&mut header)
+        .context("reading input")?;
+    if component::is_component(&header) {
+        let mut input = header;
+        reader.read_to_end(&mut input).context("reading input")?;
+        let output =
+            component::squeeze_component(&input, options).context("squeezing the component")?;
+        let input_size = input.len();
+        let candidate_size = Some(output.len());
+        let (output, passthrough_reason) = match output.len() < input_size {
+            true => (output, None),
+            false => (input, Some(PassthroughReason::NotSmaller)),
+        };
+        return Ok(SqueezeOutcome {
+            passthrough_reason,
+            input_size,
+            candidate_size,
+            packed_data_size: None,
+            data_size: None,
+            init_data_size: None,
+            compression_skipped: None,
+            prologue_wat: None,
+            unpack_bench: None,
+            memory_writes: Vec::new(),
+            output,
+        });
+    }
+    let original_version = match header.get(4..8) {
+        Some(&[low, high, 0, 0]) if header.starts_with(b"\0asm") && [low, high] != [1, 0] => {
+            let version = u16::from_le_bytes([low, high]);
+            anyhow::ensure!(
+                options.best_effort && version > 1,
+                "unknown wasm binary version {version}, only version 1 is supported"
+            );
+            log::warn!("Squeezing a module of unknown binary version {version} as version 1");
+            header[4..6].copy_from_slice(&[1, 0]);
+            Some([low, high])
+        }
+        _ => None,
+    };
+    let mut outcome = squeeze_core_reader(io::Read::chain(&header[..], reader), options)?;
+    if let Some(version) = original_version {
+        outcome.output[4..6].copy_from_slice(&version);
+    }
+    Ok(outcome)
+}
+
+/// Squeeze the core wasm module of version 1 read from the `reader`
+fn squeeze_core_reader<R: io::Read>(
+    reader: R,
+    options: &SqueezeOptions,
+) -> anyhow::Result<SqueezeOutcome> {
+    let mut info = RelevantInfoBuilder::new();
+    let input = stream::walk(reader, WASM_FEATURES, |payload| info.add_payload(payload))
+        .context("parsing input as wasm module")?;
+    squeeze_core(Cow::Owned(input), info, options)
+}
+
+/// Squeeze the core wasm module `input` of version 1 with its relevant info already collected
+fn squeeze_core(
+    input: Cow<[u8]>,
+    info: RelevantInfoBuilder,
+    options: &SqueezeOptions,
+) -> anyhow::Result<SqueezeOutcome> {
+    if !options.no_inject && unsqueeze::is_squeezed(&input, options.target) {
+        if !options.resqueeze {
+            return Ok(SqueezeOutcome {
+                passthrough_reason: Some(PassthroughReason::AlreadySqueezed),
+                input_size: input.len(),
+                candidate_size: None,
+                packed_data_size: None,
+                data_size: None,
+                init_data_size: None,
+                compression_skipped: None,
+                prologue_wat: None,
+                unpack_bench: None,
+                memory_writes: Vec::new(),
+                output: input.into_owned(),
+            });
+        }
+        log::info!("Input is already squeezed, unsqueezing it first");
+        let original = unsqueeze(&input, options.target).context("unsqueezing the input")?;
+        let mut outcome = squeeze_slice(&original, options)?;
+        outcome.input_size = input.len();
+        if input.len() <= outcome.output.len() {
+            outcome.output = input.into_owned();
+            outcome.passthrough_reason = Some(PassthroughReason::NotSmaller);
+        }
+        return Ok(outcome);
+    }
+    if options.no_inject {
+        let mut module = we::Module::new();
+        Stripper(options)
+            .parse_core_module(&mut module, wp::Parser::new(0), &input)
+            .context("reencoding input wasm module")?;
+        let output = module.finish();
+        return Ok(SqueezeOutcome {
+            passthrough_reason: None,
+            input_size: input.len(),
+            candidate_size: Some(output.len()),
+            packed_data_size: None,
+            data_size: None,
+            init_data_size: None,
+            compression_skipped: None,
+            prologue_wat: None,
+            unpack_bench: None,
+            memory_writes: Vec::new(),
+            output,
+        });
+    }
+    squeeze_module(&input, info, options)
+}
+
+/// Result of squeezing a module
+#[derive(Debug)]
+pub struct SqueezeOutcome {
+    /// Output module, which is the input module in case of a pass through
+    pub output: Vec<u8>,
+    /// Why the input was passed through instead of being squeezed
+    pub passthrough_reason: Option<PassthroughReason>,
+    pub input_size: usize,
+    /// Size of the squeezed module, even if it was rejected
+    pub candidate_size: Option<usize>,
+    pub packed_data_size: Option<usize>,
+    /// Size of merged data with zero gaps between segments, if there is any
+    pub data_size: Option<usize>,
+    /// Bytes of the merged data initialized by the input's segments, without the gaps
+    pub init_data_size: Option<usize>,
+    /// Why data is left uncompressed in the output, which only merges data segments then
+    pub compression_skipped: Option<PassthroughReason>,
+    /// Injected decompression prologue in the text format
+    pub prologue_wat: Option<String>,
+    /// Cost of running the unpacker on the compressed data, if requested
+    pub unpack_bench: Option<UnpackBench>,
+    /// Memory written by the injected code and its data segment, if data is compressed
+    pub memory_writes: Vec<MemoryWrite>,
+}
+
+/// Range of memory written before the original start function runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryWrite {
+    pub kind: MemoryWriteKind,
+    pub range: Range<i32>,
+}
+
+/// What is written into memory before the original start function runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryWriteKind {
+    /// Compressed data placed by the data segment
+    CompressedData,
+    /// Unpacker's context
+    Context,
+    /// Decompressed data written by the unpacker
+    Staging,
+    /// Decompressed data copied into its original place
+    Destination,
+    /// Memory zeroed after the copy, except for destinations
+    Fill,
+    /// Runtime's register restored to its initial value
+    Register,
+}
+
+impl fmt::Display for MemoryWriteKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MemoryWriteKind::CompressedData => "compressed data",
+            MemoryWriteKind::Context => "unpacker context",
+            MemoryWriteKind::Staging => "staging area",
+            MemoryWriteKind::Destination => "data destination",
+            MemoryWriteKind::Fill => "zero fill",
+            MemoryWriteKind::Register => "register",
+        }
+        .fmt(f)
+    }
+}
+
+/// Cost of decompressing data with the unpacker run by an interpreter on the host
+#[derive(Debug, Clone, Copy)]
+pub struct UnpackBench {
+    /// Fuel consumed by the interpreter, roughly the number of executed instructions
+    pub fuel: u64,
+    pub host_time: Duration,
+}
+
+/// Why the input module was passed through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PassthroughReason {
+    NoData,
+    CompressionDisabled,
+    IncompressibleData,
+    InsufficientMemory,
+    NotSmaller,
+    /// Input was squeezed already and [`SqueezeOptions::resqueeze`] was not set
+    AlreadySqueezed,
+    /// Squeezing failed on an internal invariant and [`SqueezeOptions::keep_going`] was set
+    InternalError,
+}
+
+impl fmt::Display for PassthroughReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PassthroughReason::NoData => "No data to compress",
+            PassthroughReason::CompressionDisabled => "Compression is disabled",
+            PassthroughReason::IncompressibleData => "Could not compress data into less bytes",
+            PassthroughReason::InsufficientMemory => {
+                "Decompression requires more space than the memory has"
+            }
+            PassthroughReason::NotSmaller => "Compression did not reduce wasm module's size",
+            PassthroughReason::AlreadySqueezed => "Input is already squeezed",
+            PassthroughReason::InternalError => "Squeezing failed on an internal error",
+        }
+        .fmt(f)
+    }
+}
+
+fn squeeze_module(
+    input: &[u8],
+    info: RelevantInfoBuilder,
+    options: &SqueezeOptions,
+) -> anyhow::Result<SqueezeOutcome> {
+    let passthrough = |passthrough_reason, candidate_size, packed_data_size| SqueezeOutcome {
+        output: input.to_owned(),
+        passthrough_reason: Some(passthrough_reason),
+        input_size: input.len(),
+        candidate_size,
+        packed_data_size,
+        data_size: None,
+        init_data_size: None,
+        compression_skipped: None,
+        prologue_wat: None,
+        unpack_bench: None,
+        memory_writes: Vec::new(),
+    };
+
+    let info = match info.build(input, options.report_gaps, options.max_gap) {
+        Ok(x) => x,
+        Err(err) => {
+            if err.chain().any(|cause| cause.is::<NoDataError>()) {
+                return Ok(passthrough(PassthroughReason::NoData, None, None));
+            }
+            return Err(err);
+        }
+    };
+    log::debug!("Retrieved relevant info from the input module:\n{info:#?}");
+    let data_size = Some(info.data.data.len());
+    let init_data_size = Some(info.init_bytes);
+    anyhow::ensure!(
+        options.packer == PackerConfig::default() || options.unpacker_import.is_some(),
+        "the embedded unpacker only decodes the default compressed format, import a matching \
+         unpack function with `unpacker_import` to change it"
+    );
+    if options.custom_unpacker.is_some() {
+        anyhow::ensure!(
+            options.unpacker_import.is_none(),
+            "custom unpacker is not injected when the unpack function is imported"
+        );
+        anyhow::ensure!(
+            !options.in_place,
+            "decompressing in place requires a margin known only for the embedded unpacker"
+        );
+        anyhow::ensure!(
+            !options.assert_roundtrip,
+            "round trip is only checked for data compressed with upkr"
+        );
+    }
+    // Internal errors pass the input through with `keep_going`
+    let internal_error = |err: anyhow::Error| {
+        if !(options.keep_going && err.is::<SqueezeError>()) {
+            return Err(err);
+        }
+        log::error!("{err:?}");
+        Ok(SqueezeOutcome {
+            data_size,
+            init_data_size,
+            ..passthrough(PassthroughReason::InternalError, None, None)
+        })
+    };
+    let unpacker_wasm = options.unpacker_wasm().unwrap_or(UNPACKER_WASM);
+    let unpacker = match UnpackerComponents::parse(unpacker_wasm) {
+        Ok(unpacker) => unpacker,
+        // Custom unpackers are user input, while the embedded one is expected to be valid
+        Err(err) if options.unpacker_wasm().is_some() => {
+            return Err(err.context("invalid unpacker module"))
+        }
+        Err(err) => {
+            let err =
+                SqueezeError::InternalInvariant(format!("invalid embedded unpacker: {err:#}"));
+            return internal_error(err.into());
+        }
+    };
+    let missing_features =
+        injected_code_features(options.unpacker_wasm()) & options.disabled_features;
+    anyhow::ensure!(
+        missing_features.is_empty(),
+        "injected code requires features disabled for the target: {}",
+        OPTIONAL_FEATURES
+            .iter()
+            .filter(|(_, feature)| missing_features.contains(*feature))
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    let (module, packed, prologue_wat) =
+        match reencode_with_unpacker(input, &info, unpacker, options) {
+            Ok(reencoded) => reencoded,
+            Err(err) => return internal_error(err),
+        };
+    let output = module.finish();
+
+    let packed_data_size = packed.as_ref().ok().map(|packed| packed.data.len());
+    if packed.is_err() || input.len() <= output.len() {
+        let reason = packed.as_ref().err().copied();
+        let reason = reason.unwrap_or(PassthroughReason::NotSmaller);
+        // Failed packing already produced merged data without the unpacker
+        if options.merge_fallback && packed.is_ok() {
+            let merged = reencode_merged(input, &info, options)?.finish();
+            if merged.len() < input.len() {
+                log::info!("{reason}, emitting merged data uncompressed");
+                if options.verify_exec {
+                    verify_exec(input, &merged).context("verifying the merged module")?;
+                }
+                return Ok(SqueezeOutcome {
+                    passthrough_reason: None,
+                    input_size: input.len(),
+                    candidate_size: Some(output.len()),
+                    packed_data_size,
+                    data_size,
+                    init_data_size,
+                    compression_skipped: Some(reason),
+                    prologue_wat: None,
+                    unpack_bench: None,
+                    memory_writes: Vec::new(),
+                    output: merged,
+                });
+            }
+        }
+        if input.len() <= output.len() {
+            // Uncompressed data might still come out smaller after merging, so the reason is
+            // only reported when the candidate is rejected
+            return Ok(SqueezeOutcome {
+                data_size,
+                init_data_size,
+                ..passthrough(reason, Some(output.len()), packed_data_size)
+            });
+        }
+    }
+    let unpack_bench = match &packed {
+        // Measurement is optional, so the squeezed module is still returned without it
+        Ok(packed) if options.bench_unpack => match bench_unpack(packed, unpacker_wasm) {
+            Ok(bench) => Some(bench),
+            Err(err) => {
+                log::warn!("Skipping the unpacker benchmark: {err:#}");
+                None
+            }
+        },
+        _ => None,
+    };
+    if options.verify_exec {
+        verify_exec(input, &output).context("verifying the squeezed module")?;
+    }
+    let memory_writes = match &packed {
+        Ok(packed) => packed.layout.memory_writes(options.target),
+        Err(_) => Vec::new(),
+    };
+    Ok(SqueezeOutcome {
+        passthrough_reason: None,
+        input_size: input.len(),
+        candidate_size: Some(output.len()),
+        packed_data_size,
+        data_size,
+        init_data_size,
+        compression_skipped: packed.err(),
+        prologue_wat,
+        unpack_bench,
+        memory_writes,
+        output,
+    })
+}
+
+/// What this build supports, reported by [`capabilities`]
+#[derive(Debug)]
+pub struct Capabilities {
+    /// Compression formats the injectable unpackers decode
+    pub codecs: &'static [&'static str],
+    pub targets: &'static [Target],
+    /// Names of the optional features injected code may do without
+    pub optional_features: Vec<&'static str>,
+    /// Benchmarking the unpacker under an interpreter, the `bench` feature
+    pub bench: bool,
+    /// Native unpack function for embedders, the `host-upkr` feature
+    pub host_upkr: bool,
+    /// Carts bundled into HTML pages, the `bundle` feature
+    pub bundle: bool,
+    /// Comparing memory left by start functions under an interpreter, the `verify-exec` feature
+    pub verify_exec: bool,
+}
+
+/// Report which codecs, targets and optional components are available in this build
+pub fn capabilities() -> Capabilities {
+    let required = injected_code_features(Some(UNPACKER_WASM));
+    Capabilities {
+        codecs: &["upkr"],
+        targets: &Target::ALL,
+        optional_features: OPTIONAL_FEATURES
+            .iter()
+            .filter(|(_, feature)| !required.contains(*feature))
+            .map(|&(name, _)| name)
+            .collect(),
+        bench: cfg!(feature = "bench"),
+        host_upkr: cfg!(feature = "host-upkr"),
+        bundle: cfg!(feature = "bundle"),
+        verify_exec: cfg!(feature = "verify-exec"),
+    }
+}
+
+/// Requirement for squeezing reported by [`check`]
+#[derive(Debug)]
+pub struct Requirement {
+    pub description: &'static str,
+    pub status: RequirementStatus,
+}
+
+#[derive(Debug)]
+pub enum RequirementStatus {
+    /// Requirement is met, with an optional note
+    Passed(String),
+    Failed(anyhow::Error),
+    /// Requirement was not checked because of an earlier failure
+    Skipped,
+}
+
+/// Run only the analysis phase and return a matrix of squeezing requirements
+pub fn check<R: io::Read>(input: R) -> Vec<Requirement> {
+    let mut requirements = Vec::new();
+    let mut report = |description, result: Option<anyhow::Result<String>>| {
+        requirements.push(Requirement {
+            description,
+            status: match result {
+                None => RequirementStatus::Skipped,
+                Some(Ok(note)) => RequirementStatus::Passed(note),
+                Some(Err(err)) => RequirementStatus::Failed(err),
+            },
+        })
+    };
+
+    let mut info = RelevantInfoBuilder::new();
+    let mut analysis = Ok(());
+    let input = stream::walk(input, WASM_FEATURES, |payload| {
+        if analysis.is_ok() {
+            analysis = info.add_payload(payload);
+        }
+        Ok(())
+    });
+    let input = match input {
+        Ok(input) => {
+            report("input is a wasm module", Some(Ok(String::new())));
+            Some(input)
+        }
+        Err(err) => {
+            report("input is a wasm module", Some(Err(err)));
+            None
+        }
+    };
+    report(
+        "wasm features are supported",
+        input.as_ref().map(|input| {
+            wp::Validator::new_with_features(WASM_FEATURES)
+                .validate_all(input)
+                .map(|_| String::new())
+                .map_err(anyhow::Error::from)
+        }),
+    );
+    let analysis = input.as_ref().map(|_| analysis);
+    let analysis_ok = matches!(analysis, Some(Ok(())));
+    report(
+        "single memory with evaluable data offsets",
+        analysis.map(|result| result.map(|()| String::new())),
+    );
+
+    let info = match &input {
+        Some(input) if analysis_ok => Some(info.build(input, usize::MAX, None)),
+        _ => None,
+    };
+    let (info, build_result) = match info {
+        Some(Ok(info)) => (Some(info), Some(Ok(String::new()))),
+        Some(Err(err)) => (None, Some(Err(err))),
+        None => (None, None),
+    };
+    report("has data to compress", build_result);
+    report(
+        "data fits memory layout",
+        info.as_ref().map(|info| {
+            // Compressed data takes at least a byte
+            anyhow::ensure!(
+                LayoutPlan::new(info.data.range()?, 1, false, info.memory_size, 0).is_some(),
+                "merged data of {} bytes leaves no room for compressed data in {} bytes of memory",
+                info.data.data.len(),
+                info.memory_size
+            );
+            Ok(format!(
+                "{} bytes of merged data, {} bytes of memory",
+                info.data.data.len(),
+                info.memory_size
+            ))
+        }),
+    );
+    report(
+        "entry point found",
+        info.as_ref().map(|info| {
+            Ok(match info.start_fn_idx {
+                Some(idx) => format!("start function {idx}"),
+                None => "start function will be created".to_owned(),
+            })
+        }),
+    );
+
+    requirements
+}
+
+/// Section sizes and data statistics reported by [`inspect`]
+#[derive(Debug)]
+pub struct Inspection {
+    /// Name and size in bytes of every section, in order
+    pub sections: Vec<(String, usize)>,
+    /// Offset and length of every data segment, with no offset for passive or relative ones
+    pub data_segments: Vec<(Option<u64>, usize)>,
+    /// Order-0 entropy of the data segments' bytes in bits per byte
+    pub data_entropy: f64,
+    /// Outcome of squeezing with default options
+    pub squeeze: anyhow::Result<SqueezeOutcome>,
+}
+
+/// Collect section sizes and data statistics, and predict savings by squeezing with default
+/// options without writing anything
+pub fn inspect<R: io::Read>(input: R) -> anyhow::Result<Inspection> {
+    const SECTION_NAMES: [&str; 14] = [
+        "custom",
+        "type",
+        "import",
+        "function",
+        "table",
+        "memory",
+        "global",
+        "export",
+        "start",
+        "element",
+        "code",
+        "data",
+        "datacount",
+        "tag",
+    ];
+
+    let mut sections = Vec::new();
+    let mut data_segments = Vec::new();
+    let mut byte_counts = [0usize; 256];
+    let input = stream::walk(input, WASM_FEATURES, |payload| {
+        let name = match &payload {
+            wp::Payload::CustomSection(section) => Some(format!("custom {:?}", section.name())),
+            payload => payload.as_section().map(|(id, _)| {
+                SECTION_NAMES
+                    .get(usize::from(id))
+                    .map_or_else(|| format!("unknown {id}"), |name| name.to_string())
+            }),
+        };
+        if let (Some(name), Some((_, range))) = (name, payload.as_section()) {
+            sections.push((name, range.len()));
+        }
+        if let wp::Payload::DataSection(segments) = payload {
+            for segment in segments {
+                let segment = segment?;
+                let offset = match segment.kind {
+                    wp::DataKind::Active { offset_expr, .. } => eval_offset(&offset_expr, &[])
+                        .ok()
+                        .and_then(|(base, offset)| base.is_none().then_some(offset)),
+                    wp::DataKind::Passive => None,
+                };
+                data_segments.push((offset, segment.data.len()));
+                for &byte in segment.data {
+                    byte_counts[usize::from(byte)] += 1;
+                }
+            }
+        }
+        Ok(())
+    })
+    .context("parsing input as wasm module")?;
+
+    let data_len: usize = byte_counts.iter().sum();
+    let data_entropy = byte_counts
+        .iter()
+        .filter(|&&count| count != 0)
+        .map(|&count| {
+            let p = count as f64 / data_len as f64;
+            -p * p.log2()
+        })
+        .sum();
+    Ok(Inspection {
+        sections,
+        data_segments,
+        data_entropy,
+        squeeze: squeeze_reader(&input[..], &SqueezeOptions::default()),
+    })
+}
+
+#[derive(Debug)]
+struct RelevantInfo {
+    start_fn_idx: Option<u32>,
+    data: Data<Vec<u8>>,
+    /// Ranges of `data` which are compressed separately, covering it whole with a single range
+    /// unless merging is limited
+    clusters: Vec<Range<usize>>,
+    /// Indices of passive data segments in the input module, which follow the merged segment
+    passive_data: Vec<u32>,
+    /// Global holding the base address `data` is relative to, such as `__memory_base`
+    data_base: Option<u32>,
+    /// Bytes the loader reserves at the base address, from the `dylink.0` custom section
+    dylink_memory_size: Option<u32>,
+    import_global_count: u32,
+    /// Mutable globals with non-zero constant initializers, by their indices
+    mutable_globals: Vec<(u32, GlobalValue)>,
+    index_spaces: IndexSpaces,
+    /// Initial size of the memory in bytes
+    memory_size: i32,
+    /// Bytes of `data` initialized by the input's segments
+    init_bytes: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Data<D> {
+    /// Address in the memory, offsets are unsigned
+    offset: u64,
+    data: D,
+}
+
+impl fmt::Debug for Data<Vec<u8>> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Data")
+            .field("offset", &self.offset)
+            .field("data", &format_args!("[u8; {}]", self.data.len()))
+            .finish()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Data<Range<T>> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Data")
+            .field("offset", &self.offset)
+            .field(
+                "data",
+                &format_args!("{:?}..{:?}", self.data.start, self.data.end),
+            )
+            .finish()
+    }
+}
+
+/// Constant initializer of a global
+#[derive(Debug, Clone, Copy)]
+enum GlobalValue {
+    I32(i32),
+    I64(i64),
+    F32(u32),
+    F64(u64),
+}
+
+impl GlobalValue {
+    /// Parse a single non-zero constant
+    fn parse(expr: &wp::ConstExpr) -> Option<Self> {
+        let mut reader = expr.get_operators_reader();
+        let value = match reader.read().ok()? {
+            wp::Operator::I32Const { value } => GlobalValue::I32(value),
+            wp::Operator::I64Const { value } => GlobalValue::I64(value),
+            wp::Operator::F32Const { value } => GlobalValue::F32(value.bits()),
+            wp::Operator::F64Const { value } => GlobalValue::F64(value.bits()),
+            _ => return None,
+        };
+        let single = matches!(reader.read().ok()?, wp::Operator::End);
+        (single && !value.to_le_bytes().iter().all(|&byte| byte == 0)).then_some(value)
+    }
+
+    fn to_le_bytes(self) -> Vec<u8> {
+        match self {
+            GlobalValue::I32(value) => value.to_le_bytes().to_vec(),
+            GlobalValue::I64(value) => value.to_le_bytes().to_vec(),
+            GlobalValue::F32(bits) => bits.to_le_bytes().to_vec(),
+            GlobalValue::F64(bits) => bits.to_le_bytes().to_vec(),
+        }
+    }
+
+    /// Zero initializer of the same type
+    fn zero(self) -> we::ConstExpr {
+        match self {
+            GlobalValue::I32(_) => we::ConstExpr::i32_const(0),
+            GlobalValue::I64(_) => we::ConstExpr::i64_const(0),
+            GlobalValue::F32(_) => we::ConstExpr::f32_const(0.0),
+            GlobalValue::F64(_) => we::ConstExpr::f64_const(0.0),
+        }
+    }
+
+    /// Instruction loading the value from an unaligned address
+    fn load(self) -> we::Instruction<'static> {
+        let memarg = we::MemArg {
+            offset: 0,
+            align: 0,
+            memory_index: 0,
+        };
+        match self {
+            GlobalValue::I32(_) => we::Instruction::I32Load(memarg),
+            GlobalValue::I64(_) => we::Instruction::I64Load(memarg),
+            GlobalValue::F32(_) => we::Instruction::F32Load(memarg),
+            GlobalValue::F64(_) => we::Instruction::F64Load(memarg),
+        }
+    }
+}
+
+impl RelevantInfo {
+    /// Data segment index in the output module for the input module's `old_idx`. Active segments
+    /// all map to the merged one, as they are equally dropped after instantiation.
+    fn data_index(&self, old_idx: u32) -> u32 {
+        match self.passive_data.binary_search(&old_idx) {
+            Ok(pos) => 1 + pos as u32,
+            Err(_) => 0,
+        }
+    }
+
+    /// Data count of the output module, the merged segment followed by passive ones
+    fn data_count(&self) -> u32 {
+        1 + self.passive_data.len() as u32
+    }
+}
+
+impl Data<Range<usize>> {
+    fn parse_slice<'a>(
+        &self,
+        module: &'a [u8],
+        globals: &[Option<i32>],
+    ) -> anyhow::Result<Data<&'a [u8]>> {
+        let mut reader =
+            wp::BinaryReader::new(&module[self.data.clone()], self.data.start, WASM_FEATURES);
+        let data = wp::Data::from_reader(&mut reader)?;
+
+        #[cfg(debug_assertions)]
+        {
+            let wp::DataKind::Active {
+                memory_index,
+                offset_expr,
+            } = data.kind
+            else {
+                panic!("parsed data kind mismatch")
+            };
+            debug_assert_eq!(memory_index, 0, "multimemory is not supported");
+            debug_assert_eq!(
+                eval_offset(&offset_expr, globals)
+                    .context("evaluating data offset")?
+                    .1,
+                self.offset,
+                "parsed data offset mismatch"
+            );
+        }
+
+        Ok(Data {
+            data: data.data,
+            offset: self.offset,
+        })
+    }
+}
+
+impl Data<Vec<u8>> {
+    /// Address range of the data, which is expected to lie below 2GiB
+    fn range(&self) -> anyhow::Result<Range<i32>> {
+        let start = i32::try_from(self.offset).context("data offset is too large")?;
+        let len = i32::try_from(self.data.len()).context("data is too large")?;
+        let end = start.checked_add(len).context("data end overflows")?;
+        Ok(start..end)
+    }
+
+    /// Copy of the data in the `range` relative to its start
+    fn slice(&self, range: Range<usize>) -> Data<Vec<u8>> {
+        Data {
+            offset: self.offset + range.start as u64,
+            data: self.data[range].to_vec(),
+        }
+    }
+
+    /// Split the data at runs of at least `min_gap` zero bytes, which are trimmed along with
+    /// trailing zeros
+    fn split_at_gaps(&self, min_gap: usize) -> Vec<Data<Vec<u8>>> {
+        let mut clusters = Vec::new();
+        let mut start = 0;
+        let mut zeros = 0;
+        for (i, &byte) in self.data.iter().enumerate() {
+            if byte == 0 {
+                zeros += 1;
+                continue;
+            }
+            if zeros >= min_gap.max(1) {
+                if start < i - zeros {
+                    clusters.push(self.slice(start..i - zeros));
+                }
+                start = i;
+            }
+            zeros = 0;
+        }
+        if start < self.data.len() - zeros {
+            clusters.push(self.slice(start..self.data.len() - zeros));
+        }
+        clusters
+    }
+
+    /// Append this data as an active segment of the first memory
+    fn encode_active(
+        &self,
+        section: &mut we::DataSection,
+        base: Option<u32>,
+    ) -> Result<(), SqueezeError> {
+        let offset = u32::try_from(self.offset).map_err(|_| {
+            SqueezeError::InternalInvariant(format!("data offset {:#x} overflows", self.offset))
+        })?;
+        // `i32.const` holds the unsigned address bit for bit
+        let offset = offset_expr(offset as i32, base);
+        section.active(0, &offset, self.data.iter().copied());
+        Ok(())
+    }
+}
+
+/// Data segment offset, relative to the `base` global if there is one
+fn offset_expr(offset: i32, base: Option<u32>) -> we::ConstExpr {
+    match base {
+        None => we::ConstExpr::i32_const(offset),
+        Some(global) if offset == 0 => we::ConstExpr::global_get(global),
+        Some(global) => {
+            let mut bytes = Vec::new();
+            we::Instruction::GlobalGet(global).encode(&mut bytes);
+            we::Instruction::I32Const(offset).encode(&mut bytes);
+            we::Instruction::I32Add.encode(&mut bytes);
+            we::ConstExpr::raw(bytes)
+        }
+    }
+}
+
+impl Data<&[u8]> {
+    fn to_vec(&self) -> Data<Vec<u8>> {
+        Data {
+            offset: self.offset,
+            data: self.data.to_owned(),
+        }
+    }
+}
+
+/// Function and type index spaces of the output module, which consists of the input module's
+/// entities followed by the unpacker's ones and then by the injected ones. An imported unpack
+/// function follows the input module's imports, shifting its defined functions.
+#[derive(Debug, Clone, Copy)]
+struct IndexSpaces {
+    import_function_count: u32,
+    old_function_count: u32,
+    old_type_count: u32,
+    unpacker_function_count: u32,
+    unpacker_type_count: u32,
+    injected_import_count: u32,
+}
+
+impl IndexSpaces {
+    fn new(import_function_count: u32, old_function_count: u32, old_type_count: u32) -> Self {
+        IndexSpaces {
+            import_function_count,
+            old_function_count,
+            old_type_count,
+            unpacker_function_count: 0,
+            unpacker_type_count: 0,
+            injected_import_count: 0,
+        }
+    }
+
+    fn with_unpacker(self, unpacker: &UnpackerComponents) -> Self {
+        IndexSpaces {
+            unpacker_function_count: unpacker.functions.count(),
+            unpacker_type_count: unpacker.types.count(),
+            ..self
+        }
+    }
+
+    /// Index spaces with the unpack function imported instead of the unpacker's code, which
+    /// only brings the unpack function's type
+    fn with_imported_unpacker(self) -> Self {
+        IndexSpaces {
+            unpacker_type_count: 1,
+            injected_import_count: 1,
+            ..self
+        }
+    }
+
+    /// Check that indices of all functions and types, including the injected start function and
+    /// its type, fit into `u32`, so the index arithmetic never overflows
+    fn checked(self) -> Result<Self, SqueezeError> {
+        let function_count = [
+            self.injected_import_count,
+            self.old_function_count,
+            self.unpacker_function_count,
+            1,
+        ]
+        .into_iter()
+        .try_fold(self.import_function_count, u32::checked_add);
+        let type_count = [self.unpacker_type_count, 1]
+            .into_iter()
+            .try_fold(self.old_type_count, u32::checked_add);
+        match (function_count, type_count) {
+            (Some(_), Some(_)) => Ok(self),
+            _ => Err(SqueezeError::InternalInvariant(format!(
+                "input module's {} functions and {} types with the unpacker's {} functions and {} \
+                 types overflow the index spaces",
+                u64::from(self.import_function_count) + u64::from(self.old_function_count),
+                self.old_type_count,
+                self.unpacker_function_count,
+                self.unpacker_type_count,
+            ))),
+        }
+    }
+
+    /// Function index of the `code_idx`-th defined function
+    fn defined_function(&self, code_idx: u32) -> u32 {
+        self.import_function_count + self.injected_import_count + code_idx
+    }
+
+    /// Function index of the input module's function `old_idx`
+    fn old_function(&self, old_idx: u32) -> u32 {
+        if old_idx < self.import_function_count {
+            old_idx
+        } else {
+            old_idx + self.injected_import_count
+        }
+    }
+
+    /// Function index of the imported unpack function
+    fn imported_unpack_fn(&self) -> u32 {
+        self.import_function_count
+    }
+
+    /// Type index of the imported unpack function
+    fn unpack_fn_type(&self) -> u32 {
+        self.old_type_count
+    }
+
+    fn unpacker_reencoder(&self) -> AdaptUnpacker {
+        AdaptUnpacker {
+            functions_index_base: self.defined_function(self.old_function_count),
+            types_index_base: self.old_type_count,
+        }
+    }
+
+    /// Function index of the unpacker's `unpacker_idx`-th function
+    fn unpacker_function(&self, unpacker_idx: u32) -> u32 {
+        self.defined_function(self.old_function_count + unpacker_idx)
+    }
+
+    /// Type index of the injected `[] -> []` function type
+    fn subroutine_fn_type(&self) -> u32 {
+        self.old_type_count + self.unpacker_type_count
+    }
+
+    /// Function index of the injected start function, if there's no start function already
+    fn injected_start_fn(&self) -> u32 {
+        self.unpacker_function(self.unpacker_function_count)
+    }
+}
+
+struct RelevantInfoBuilder {
+    start_fn_idx: Option<u32>,
+    data: Vec<Data<Range<usize>>>,
+    old_functions: Option<Vec<u32>>,
+    old_type_count: Option<u32>,
+    import_function_count: Option<u32>,
+    has_data_count: bool,
+    name_strings_len: usize,
+    memory_type: Option<wp::MemoryType>,
+    data_segment_count: u32,
+    passive_data: Vec<u32>,
+    /// Values of immutable `i32` globals, if they are known
+    globals: Vec<Option<i32>>,
+    /// Global which all of the data offsets are relative to, if any
+    data_base: Option<Option<u32>>,
+    dylink_memory_size: Option<u32>,
+    import_global_count: u32,
+    mutable_globals: Vec<(u32, GlobalValue)>,
+}
+
+impl RelevantInfoBuilder {
+    fn new() -> Self {
+        Self {
+            start_fn_idx: None,
+            data: Vec::new(),
+            old_functions: None,
+            old_type_count: None,
+            import_function_count: None,
+            has_data_count: false,
+            name_strings_len: 0,
+            memory_type: None,
+            data_segment_count: 0,
+            passive_data: Vec::new(),
+            globals: Vec::new(),
+            data_base: None,
+            dylink_memory_size: None,
+            import_global_count: 0,
+            mutable_globals: Vec::new(),
+        }
+    }
+
+    fn add_payload(&mut self, payload: wp::Payload) -> anyhow::Result<()> {
+        match payload {
+            wp::Payload::DataCountSection { .. } => {
+                anyhow::ensure!(
+                    !self.has_data_count,
+                    "encountered multiple data count sections"
+                );
+                self.has_data_count = true;
+            }
+            wp::Payload::DataSection(data) => {
+                // Some tools emit multiple data sections, their entries get merged anyway
+                if !self.data.is_empty() {
+                    log::debug!("Encountered multiple data sections, accumulating their entries");
+                }
+                self.data.reserve(data.count().try_into()?);
+                for data in data {
+                    let data = data?;
+                    let data_idx = self.data_segment_count;
+                    self.data_segment_count += 1;
+                    let wp::DataKind::Active {
+                        memory_index,
+                        offset_expr,
+                    } = &data.kind
+                    else {
+                        // Passive segments are kept as is
+                        self.passive_data.push(data_idx);
+                        continue;
+                    };
+                    anyhow::ensure!(*memory_index == 0, "multi memory is not supported");
+                    let (base, offset) = eval_offset(offset_expr, &self.globals)
+                        .context("evaluating a data offset expression")?;
+                    match self.data_base {
+                        None => self.data_base = Some(base),
+                        Some(data_base) => anyhow::ensure!(
+                            data_base == base,
+                            "data offsets are relative to different globals"
+                        ),
+                    }
+                    self.data.push(Data {
+                        data: data.range,
+                        offset,
+                    })
+                }
+            }
+            wp::Payload::ImportSection(imports) => {
+                anyhow::ensure!(
+                    self.import_function_count.is_none(),
+                    "encountered multiple import sections"
+                );
+                let mut import_function_count = 0;
+                for import in imports {
+                    let import = import?;
+                    self.name_strings_len += string_encoded_len(import.module)?;
+                    self.name_strings_len += string_encoded_len(import.name)?;
+                    match import.ty {
+                        wp::TypeRef::Func(_) => import_function_count += 1,
+                        // Imported memories come first in the index space
+                        wp::TypeRef::Memory(ty) if self.memory_type.is_none() => {
+                            self.memory_type = Some(ty)
+                        }
+                        // Values of imported globals are unknown
+                        wp::TypeRef::Global(_) => self.globals.push(None),
+                        _ => {}
+                    }
+                }
+                self.import_function_count = Some(import_function_count);
+            }
+            wp::Payload::FunctionSection(functions) => {
+                anyhow::ensure!(
+                    self.old_functions.is_none(),
+                    "encountered multiple function sections"
+                );
+                self.old_functions = Some(functions.into_iter().collect::<Result<_, _>>()?);
+            }
+            wp::Payload::TypeSection(types) => {
+                anyhow::ensure!(
+                    self.old_type_count.is_none(),
+                    "encountered multiple type sections"
+                );
+                self.old_type_count = Some(types.count());
+            }
+            wp::Payload::StartSection { func, .. } => {
+                anyhow::ensure!(self.start_fn_idx.is_none(), "found multiple start sections");
+                self.start_fn_idx = Some(func);
+            }
+            wp::Payload::GlobalSection(globals) => {
+                self.import_global_count = self.globals.len().try_into()?;
+                for global in globals {
+                    let global = global?;
+                    if global.ty.mutable {
+                        if let Some(value) = GlobalValue::parse(&global.init_expr) {
+                            let index = self.globals.len().try_into()?;
+                            self.mutable_globals.push((index, value));
+                        }
+                    }
+                    let constant = !global.ty.mutable && global.ty.content_type == wp::ValType::I32;
+                    let value = constant
+                        .then(|| eval_i32(&global.init_expr, &self.globals).ok())
+                        .flatten();
+                    self.globals.push(value);
+                }
+            }
+            wp::Payload::MemorySection(memories) => {
+                for memory in memories {
+                    let memory = memory?;
+                    self.memory_type.get_or_insert(memory);
+                }
+            }
+            wp::Payload::ExportSection(exports) => {
+                for export in exports {
+                    self.name_strings_len += string_encoded_len(export?.name)?;
+                }
+            }
+            wp::Payload::CustomSection(section) if section.name() == "dylink.0" => {
+                self.dylink_memory_size =
+                    dylink_memory_size(&section).context("parsing the dylink.0 section")?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Return info with data merged from the `input` module's segments. Data is split into
+    /// clusters at gaps larger than `max_gap`.
+    fn build(
+        mut self,
+        input: &[u8],
+        report_gaps: usize,
+        max_gap: Option<usize>,
+    ) -> anyhow::Result<RelevantInfo> {
+        if self.data.is_empty() {
+            return Err(NoDataError.into());
+        }
+
+        let data_base = self.data_base.flatten();
+        if let Some(global) = data_base {
+            log::info!("Data offsets are relative to the global {global}");
+        }
+
+        // zero sized data is't supported
+        self.data.sort_unstable_by_key(|d| d.offset);
+
+        // Merge data sections
+        let mut data = self.data.iter();
+        let first_data = data.next().unwrap().parse_slice(input, &self.globals)?;
+        let mut init_bytes = first_data.data.len();
+        let mut output_data = first_data.to_vec();
+        let mut gaps = Vec::new();
+        let mut clusters = Vec::new();
+        let mut cluster_start = 0;
+
+        for data in data {
+            let data = data.parse_slice(input, &self.globals)?;
+            init_bytes += data.data.len();
+            // Sorted by offset, so this does not underflow
+            let new_len = usize::try_from(data.offset - output_data.offset)
+                .context("data sections are too far apart")?;
+            anyhow::ensure!(output_data.data.len() <= new_len, "data sections overlap");
+            let gap_len = new_len - output_data.data.len();
+            if gap_len >= report_gaps {
+                gaps.push(Data {
+                    offset: output_data.offset + u64::try_from(output_data.data.len())?,
+                    data: gap_len,
+                });
+            }
+            // Clusters are only supported at known addresses
+            if max_gap.is_some_and(|max_gap| gap_len > max_gap) && data_base.is_none() {
+                clusters.push(cluster_start..output_data.data.len());
+                cluster_start = new_len;
+            }
+            output_data.data.resize(new_len, 0);
+            output_data.data.extend_from_slice(data.data);
+        }
+        clusters.push(cluster_start..output_data.data.len());
+        if clusters.len() > 1 {
+            log::info!(
+                "Gaps larger than {} bytes split data into {} clusters",
+                max_gap.unwrap(),
+                clusters.len()
+            );
+        }
+        log::info!(
+            "Data section's memory has {:.2}% of initialized bytes",
+            100.0 * init_bytes as f64 / output_data.data.len() as f64
+        );
+        for gap in &gaps {
+            log::info!(
+                "Zero gap of {} bytes at {:#x}..{:#x}",
+                gap.data,
+                gap.offset,
+                gap.offset + u64::try_from(gap.data)?
+            );
+        }
+        log::info!(
+            "Import and export names take {} bytes",
+            self.name_strings_len
+        );
+        let gaps_len: usize = gaps.iter().map(|gap| gap.data).sum();
+        if !gaps.is_empty() && 2 * gaps_len >= output_data.data.len() {
+            log::info!(
+                "Reported gaps take {:.2}% of merged data, compressing segments separately might help",
+                100.0 * gaps_len as f64 / output_data.data.len() as f64
+            );
+        }
+
+        let old_functions = self
+            .old_functions
+            .context("no function section encountered")?;
+        Ok(RelevantInfo {
+            // Import section might come in any order, so index spaces are computed at the end
+            index_spaces: IndexSpaces::new(
+                self.import_function_count.unwrap_or(0),
+                old_functions.len().try_into()?,
+                self.old_type_count.context("no type section was found")?,
+            ),
+            start_fn_idx: self.start_fn_idx,
+            passive_data: self.passive_data,
+            data_base,
+            dylink_memory_size: self.dylink_memory_size,
+            import_global_count: self.import_global_count,
+            mutable_globals: self.mutable_globals,
+            data: output_data,
+            clusters,
+            init_bytes,
+            memory_size: match self.memory_type {
+                Some(ty) => {
+                    // Offsets are kept as i32, so larger memories are truncated
+                    let size = ty.initial.saturating_mul(PAGE_SIZE);
+                    i32::try_from(size).unwrap_or(i32::MAX & !(PAGE_SIZE as i32 - 1))
+                }
+                None => {
+                    log::warn!("No memory found, assuming {DEFAULT_MEM_SIZE} bytes");
+                    DEFAULT_MEM_SIZE
+                }
+            },
+        })
+    }
+}
+
+/// Errors of squeezing which may be handled specially
+#[derive(Debug)]
+pub enum SqueezeError {
+    /// Input module broke assumptions of the transformation, which is a bug or an unsupported
+    /// module
+    InternalInvariant(String),
+}
+
+impl fmt::Display for SqueezeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SqueezeError::InternalInvariant(msg) => write!(f, "internal invariant violated: {msg}"),
+        }
+    }
+}
+
+impl Error for SqueezeError {}
+
+macro_rules! invariant_violated {
+    ($($arg:tt)+) => {
+        return Err(reencode::Error::UserError(SqueezeError::InternalInvariant(format!($($arg)+))))
+    };
+}
+
+macro_rules! ensure_invariant {
+    ($cond:expr, $($arg:tt)+) => {
+        if !$cond {
+            invariant_violated!($($arg)+);
+        }
+    };
+}
+
+#[derive(Debug)]
+struct NoDataError;
+
+impl fmt::Display for NoDataError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        "no data to compress".fmt(f)
+    }
+}
+
+impl Error for NoDataError {}
+
+struct UnpackerComponents<'a> {
+    types: wp::TypeSectionReader<'a>,
+    functions: wp::FunctionSectionReader<'a>,
+    function_bodies: Vec<wp::FunctionBody<'a>>,
+    unpack_fn_idx: u32,
+}
+
+impl<'a> UnpackerComponents<'a> {
+    /// Parse the unpacker module, checking that it can be injected
+    fn parse(data: &'a [u8]) -> anyhow::Result<Self> {
+        wp::Validator::new_with_features(WASM_FEATURES)
+            .validate_all(data)
+            .context("validating the unpacker")?;
+        let mut types = None;
+        let mut functions = None;
+        let mut function_bodies = Vec::new();
+        let mut parser = wp::Parser::new(0);
+        let mut unpack_fn_idx = None;
+        parser.set_features(WASM_FEATURES);
+
+        for payload in parser.parse_all(data) {
+            match payload? {
+                wp::Payload::Version { .. }
+                | wp::Payload::CustomSection(_)
+                | wp::Payload::End(_) => (),
+                wp::Payload::TypeSection(t) => types = Some(t),
+                wp::Payload::ImportSection(imports) => {
+                    for import in imports {
+                        let import = import?;
+                        anyhow::ensure!(
+                            (import.module, import.name) == ("env", "memory")
+                                && matches!(import.ty, wp::TypeRef::Memory(_)),
+                            "unpacker imports {}.{}, while only `env.memory` is allowed",
+                            import.module,
+                            import.name
+                        );
+                    }
+                }
+                wp::Payload::FunctionSection(f) => functions = Some(f),
+                wp::Payload::CodeSectionStart { count, .. } => {
+                    let count = usize::try_from(count)
+                        .with_context(|| format!("unpacker's {count} functions overflow usize"))?;
+                    function_bodies.reserve(count)
+                }
+                wp::Payload::CodeSectionEntry(function) => function_bodies.push(function),
+                wp::Payload::ExportSection(exports) => {
+                    anyhow::ensure!(
+                        exports.count() == 1,
+                        "unpacker has {} exports instead of a single unpack function",
+                        exports.count()
+                    );
+                    let export = exports
+                        .into_iter()
+                        .next()
+                        .context("unpacker's export section has no entries to read")??;
+                    anyhow::ensure!(
+                        export.kind == wp::ExternalKind::Func,
+                        "unpacker exports `{}`, which is not a function",
+                        export.name
+                    );
+                    unpack_fn_idx = Some(export.index);
+                }
+                payload => match payload.as_section() {
+                    Some((id, _)) => anyhow::bail!("unpacker has a section with id {id}"),
+                    None => anyhow::bail!("unpacker has unexpected payload {payload:?}"),
+                },
+            }
+        }
+        let types = types.context("unpacker has no types")?;
+        let functions = functions.context("unpacker has no functions")?;
+        let unpack_fn_idx = unpack_fn_idx.context("unpacker exports no unpack function")?;
+
+        // Imports are memories only, so functions are indexed by the function section
+        let unpack_fn_type = functions
+            .clone()
+            .into_iter()
+            .nth(unpack_fn_idx as usize)
+            .with_context(|| {
+                format!("unpacker exports function {unpack_fn_idx}, which it does not define")
+            })??;
+        let unpack_fn_type = types
+            .clone()
+            .into_iter_err_on_gc_types()
+            .nth(unpack_fn_type as usize)
+            .context("unpack function has no type")??;
+        anyhow::ensure!(
+            unpack_fn_type.params() == [wp::ValType::I32; 3]
+                && unpack_fn_type.results() == [wp::ValType::I32],
+            "unpack function has type {unpack_fn_type:?} instead of [i32 i32 i32] -> [i32]"
+        );
+        Ok(UnpackerComponents {
+            types,
+            functions,
+            unpack_fn_idx,
+            function_bodies,
+        })
+    }
+}
+
+/// Optional features which injected code requires, found by validating the unpacker without each
+/// of them, or `None` if the unpack function is imported
+fn injected_code_features(unpacker_wasm: Option<&[u8]>) -> wp::WasmFeatures {
+    // Prologue uses `memory.copy` and `memory.fill`
+    let mut required = wp::WasmFeatures::BULK_MEMORY;
+    if let Some(unpacker_wasm) = unpacker_wasm {
+        for &(_, feature) in OPTIONAL_FEATURES {
+            let validates = wp::Validator::new_with_features(WASM_FEATURES.difference(feature))
+                .validate_all(unpacker_wasm)
+                .is_ok();
+            if !validates {
+                required |= feature;
+            }
+        }
+    }
+    required
+}
+
+#[cfg(feature = "bench")]
+use bench::bench_unpack;
+
+#[cfg(not(feature = "bench"))]
+fn bench_unpack(_packed: &PackedData, _unpacker_wasm: &[u8]) -> anyhow::Result<UnpackBench> {
+    anyhow::bail!("benchmarking the unpacker requires the `bench` feature")
+}
+
+#[cfg(feature = "verify-exec")]
+use verify::verify_exec;
+
+#[cfg(not(feature = "verify-exec"))]
+fn verify_exec(_original: &[u8], _squeezed: &[u8]) -> anyhow::Result<()> {
+    anyhow::bail!("verifying the squeezed module requires the `verify-exec` feature")
+}
+
+fn reencode_with_unpacker<'a>(
+    input_module: &[u8],
+    info: &'a RelevantInfo,
+    unpacker: UnpackerComponents<'a>,
+    options: &SqueezeOptions,
+) -> anyhow::Result<(
+    we::Module,
+    Result<PackedData, PassthroughReason>,
+    Option<String>,
+)> {
+    let mut module = we::Module::new();
+
+    let packed_data = if options.no_compress {
+        Err(PassthroughReason::CompressionDisabled)
+    } else if info.clusters.len() > 1 {
+        let clusters: Vec<_> = info
+            .clusters
+            .iter()
+            .map(|range| info.data.slice(range.clone()))
+            .collect();
+        let data_len: usize = clusters.iter().map(|cluster| cluster.data.len()).sum();
+        match pack_clusters(&clusters, info.memory_size, options)? {
+            Some(packed) if data_len <= packed.data.len() => {
+                Err(PassthroughReason::IncompressibleData)
+            }
+            Some(packed) => {
+                log::debug!("Planned memory layout: {:#?}", packed.layout);
+                Ok(packed)
+            }
+            None => Err(PassthroughReason::InsufficientMemory),
+        }
+    } else {
+        // Global values are appended to the data, which only the default layout supports
+        let restore_globals = options.compress_globals
+            && !info.mutable_globals.is_empty()
+            && !options.in_place
+            && info.data_base.is_none();
+        let mut data = info.data.data.clone();
+        if restore_globals {
+            for (_, value) in &info.mutable_globals {
+                data.extend(value.to_le_bytes());
+            }
+        }
+        let packed = options.pack(&data)?;
+        if data.len() <= packed.len() {
+            Err(PassthroughReason::IncompressibleData)
+        } else if let Some(split) = options
+            .split_gaps
+            .filter(|_| !restore_globals)
+            .map(|min_gap| pack_split(info, min_gap, packed.len(), options))
+            .transpose()?
+            .flatten()
+        {
+            log::debug!("Planned memory layout: {:#?}", split.layout);
+            Ok(split)
+        } else if let Some(layout) =
+            plan_layout(info, &packed, options, data.len() - info.data.data.len())
+        {
+            log::debug!("Planned memory layout: {layout:#?}");
+            Ok(PackedData {
+                data: packed,
+                layout,
+                globals_restored: restore_globals,
+            })
+        } else {
+            Err(PassthroughReason::InsufficientMemory)
+        }
+    };
+    anyhow::ensure!(
+        options.reserved.is_empty()
+            || !matches!(packed_data, Err(PassthroughReason::InsufficientMemory)),
+        "no memory layout for decompression avoids the reserved ranges"
+    );
+    let packed_data = match packed_data {
+        Ok(packed_data) => packed_data,
+        Err(reason) => {
+            // Nothing to unpack, so the unpacker and the prologue would be dead code
+            log::warn!("{reason}, writing old");
+            let module = reencode_merged(input_module, info, options)?;
+            return Ok((module, Err(reason), None));
+        }
+    };
+
+    let unpacker_import = options.unpacker_import.as_ref();
+    let (index_spaces, unpack_fn_idx) = match unpacker_import {
+        Some(_) => {
+            let index_spaces = info.index_spaces.with_imported_unpacker().checked()?;
+            (index_spaces, index_spaces.imported_unpack_fn())
+        }
+        None => {
+            let index_spaces = info.index_spaces.with_unpacker(&unpacker).checked()?;
+            let unpack_fn_idx = index_spaces.unpacker_function(unpacker.unpack_fn_idx);
+            (index_spaces, unpack_fn_idx)
+        }
+    };
+    let mut merger = Merger {
+        function_bodies_left: index_spaces.old_function_count,
+        unpack_fn_idx,
+        subroutine_fn_type_idx: index_spaces.subroutine_fn_type(),
+        new_start_fn_idx: match info.start_fn_idx {
+            Some(idx) => index_spaces.old_function(idx),
+            None => index_spaces.injected_start_fn(),
+        },
+        index_spaces,
+        unpacker_import,
+        imports_emitted: false,
+        info,
+        packed_data,
+        unpacker,
+        data_emitted: false,
+        renames: &options.renames,
+        target: options.target,
+        options,
+    };
+    merger
+        .parse_core_module(&mut module, wp::Parser::new(0), input_module)
+        .map_err(|err| match err {
+            reencode::Error::UserError(err) => anyhow::Error::from(err),
+            err => anyhow::Error::from(err),
+        })
+        .context("reencoding the input module with the unpacker")?;
+
+    let prologue = merger.prefix_instrs()?;
+    let prologue_len: usize = prologue
+        .iter()
+        .map(|instr| {
+            let mut bytes = Vec::new();
+            instr.encode(&mut bytes);
+            bytes.len()
+        })
+        .sum();
+    log::debug!("Injected prologue takes {prologue_len} bytes");
+    let prologue_wat = prologue_wat(&prologue);
+
+    return Ok((module, Ok(merger.packed_data), Some(prologue_wat)));
+
+    struct Merger<'a> {
+        info: &'a RelevantInfo,
+        unpacker: UnpackerComponents<'a>,
+        index_spaces: IndexSpaces,
+        function_bodies_left: u32,
+        subroutine_fn_type_idx: u32,
+        new_start_fn_idx: u32,
+        unpack_fn_idx: u32,
+        packed_data: PackedData,
+        /// Module and field of the unpack function if it is imported instead of injected
+        unpacker_import: Option<&'a (String, String)>,
+        imports_emitted: bool,
+        data_emitted: bool,
+        renames: &'a Renames,
+        target: Target,
+        options: &'a SqueezeOptions,
+    }
+
+    impl<'a> Reencode for Merger<'a> {
+        type Error = SqueezeError;
+
+        fn parse_type_section(
+            &mut self,
+            types: &mut we::TypeSection,
+            section: wp::TypeSectionReader<'_>,
+        ) -> Result<(), reencode::Error<Self::Error>> {
+            reencode::utils::parse_type_section(self, types, section)?;
+            ensure_invariant!(
+                types.len() == self.index_spaces.old_type_count,
+                "type section has {} types instead of {}",
+                types.len(),
+                self.index_spaces.old_type_count,
+            );
+            if self.unpacker_import.is_some() {
+                // unpack(context, destination, compressed) -> decompressed length
+                types.function([we::ValType::I32; 3], [we::ValType::I32]);
+            } else {
+                reencode::utils::parse_type_section(
+                    &mut self.index_spaces.unpacker_reencoder(),
+                    types,
+                    self.unpacker.types.clone(),
+                )?;
+            }
+            ensure_invariant!(
+                types.len() == self.subroutine_fn_type_idx,
+                "injected type would get index {} instead of {}",
+                types.len(),
+                self.subroutine_fn_type_idx,
+            );
+            types.function(iter::empty(), iter::empty());
+            Ok(())
+        }
+
+        fn parse_function_section(
+            &mut self,
+            functions: &mut we::FunctionSection,
+            section: wp::FunctionSectionReader<'_>,
+        ) -> Result<(), reencode::Error<Self::Error>> {
+            reencode::utils::parse_function_section(self, functions, section)?;
+            ensure_invariant!(
+                functions.len() == self.index_spaces.old_function_count,
+                "function section has {} functions instead of {}",
+                functions.len(),
+                self.index_spaces.old_function_count,
+            );
+            if self.unpacker_import.is_none() {
+                reencode::utils::parse_function_section(
+                    &mut self.index_spaces.unpacker_reencoder(),
+                    functions,
+                    self.unpacker.functions.clone(),
+                )?;
+            }
+            if self.info.start_fn_idx.is_none() {
+                ensure_invariant!(
+                    self.index_spaces.defined_function(functions.len()) == self.new_start_fn_idx,
+                    "injected start function would get index {} instead of {}",
+                    self.index_spaces.defined_function(functions.len()),
+                    self.new_start_fn_idx,
+                );
+                functions.function(self.subroutine_fn_type_idx);
+            }
+            Ok(())
+        }
+
+        fn parse_function_body(
+            &mut self,
+            code: &mut we::CodeSection,
+            func: wp::FunctionBody<'_>,
+        ) -> Result<(), reencode::Error<Self::Error>> {
+            let is_start_fn = self.info.start_fn_idx.is_some()
+                && self.index_spaces.defined_function(code.len()) == self.new_start_fn_idx;
+            if !is_start_fn {
+                reencode::utils::parse_function_body(self, code, func)?;
+            } else {
+                let mut f = self.new_function_with_parsed_locals(&func)?;
+                self.encode_prefix_instrs(&mut f)?;
+                let mut reader = func.get_operators_reader()?;
+                while !reader.eof() {
+                    self.parse_instruction(&mut f, &mut reader)?;
+                }
+                code.function(&f);
+            }
+            self.function_bodies_left = match self.function_bodies_left.checked_sub(1) {
+                Some(left) => left,
+                None => invariant_violated!(
+                    "code section has more than {} function bodies",
+                    self.index_spaces.old_function_count,
+                ),
+            };
+            if self.function_bodies_left == 0 {
+                // Last function body parsed
+                ensure_invariant!(
+                    code.len() == self.index_spaces.old_function_count,
+                    "code section has {} function bodies instead of {}",
+                    code.len(),
+                    self.index_spaces.old_function_count,
+                );
+                let mut unpacker_reencoder = self.index_spaces.unpacker_reencoder();
+                let unpacker_bodies = match self.unpacker_import {
+                    Some(_) => &[][..],
+                    None => &self.unpacker.function_bodies[..],
+                };
+                for func in unpacker_bodies {
+                    reencode::utils::parse_function_body(
+                        &mut unpacker_reencoder,
+                        code,
+                        func.clone(),
+                    )?;
+                }
+                if self.info.start_fn_idx.is_none() {
+                    ensure_invariant!(
+                        self.index_spaces.defined_function(code.len()) == self.new_start_fn_idx,
+                        "injected start function body would get index {} instead of {}",
+                        self.index_spaces.defined_function(code.len()),
+                        self.new_start_fn_idx,
+                    );
+                    let mut func = we::Function::new(iter::empty());
+                    self.encode_prefix_instrs(&mut func)?;
+                    func.instruction(&we::Instruction::End);
+                    code.function(&func);
+                }
+            }
+            Ok(())
+        }
+
+        fn parse_import_section(
+            &mut self,
+            imports: &mut we::ImportSection,
+            section: wp::ImportSectionReader<'_>,
+        ) -> Result<(), reencode::Error<Self::Error>> {
+            let renames = self.renames;
+            parse_renamed_imports(self, renames, imports, section)?;
+            self.import_unpacker(imports);
+            Ok(())
+        }
+
+        fn parse_export_section(
+            &mut self,
+            exports: &mut we::ExportSection,
+            section: wp::ExportSectionReader<'_>,
+        ) -> Result<(), reencode::Error<Self::Error>> {
+            let renames = self.renames;
+            parse_renamed_exports(self, renames, exports, section)
+        }
+
+        fn function_index(&mut self, func: u32) -> u32 {
+            self.index_spaces.old_function(func)
+        }
+
+        fn parse_global_section(
+            &mut self,
+            globals: &mut we::GlobalSection,
+            section: wp::GlobalSectionReader<'_>,
+        ) -> Result<(), reencode::Error<Self::Error>> {
+            let restored = self.packed_data.globals_restored;
+            for (i, global) in section.into_iter().enumerate() {
+                let global = global?;
+                let index = self.info.import_global_count + i as u32;
+                let value = self
+                    .info
+                    .mutable_globals
+                    .iter()
+                    .find(|&&(restored_index, _)| restored_index == index);
+                match value {
+                    // Original value is set by the prologue
+                    Some(&(_, value)) if restored => {
+                        let ty = self.global_type(global.ty)?;
+                        globals.global(ty, &value.zero());
+                    }
+                    _ => self.parse_global(globals, global)?,
+                }
+            }
+            Ok(())
+        }
+
+        fn parse_data_section(
+            &mut self,
+            data: &mut we::DataSection,
+            section: wp::DataSectionReader<'_>,
+        ) -> Result<(), reencode::Error<Self::Error>> {
+            // Compressed data is emitted into the first data section, others only keep passive data
+            if !std::mem::replace(&mut self.data_emitted, true) {
+                let packed = &self.packed_data;
+                let offset = offset_expr(packed.layout.compressed_start(), self.info.data_base);
+                data.active(0, &offset, packed.data.iter().copied());
+            }
+            parse_passive_data(data, section)
+        }
+
+        fn data_index(&mut self, data: u32) -> u32 {
+            self.info.data_index(data)
+        }
+
+        fn data_count(&mut self, _count: u32) -> u32 {
+            self.info.data_count()
+        }
+
+        fn parse_custom_section(
+            &mut self,
+            module: &mut we::Module,
+            section: wp::CustomSectionReader<'_>,
+        ) -> Result<(), reencode::Error<Self::Error>> {
+            if self.options.strips(section.name()) {
+                return Ok(());
+            }
+            // Code offsets move with the injected code and the reencoded sections
+            if section.name().starts_with(".debug_") || section.name() == "sourceMappingURL" {
+                log::warn!(
+                    "Custom section {:?} is kept, but its code offsets no longer match, strip it \
+                     with `--strip-custom` or map offsets of the squeezed module anew",
+                    section.name()
+                );
+            }
+            if section.name() == "dylink.0" && self.info.data_base.is_some() {
+                // Relative layout uses memory past the data, which the loader has to reserve
+                let memory_size = self.packed_data.layout.end() as u32;
+                let data = grow_dylink_memory_size(&section, memory_size)?;
+                module.section(&we::CustomSection {
+                    name: section.name().into(),
+                    data: data.into(),
+                });
+                return Ok(());
+            }
+            match section.as_known() {
+                wp::KnownCustom::Name(names) => {
+                    let (info, injected) = (self.info, self.injected_function_names());
+                    let data_name = "__wasm_squeeze_compressed";
+                    let names = remap_names(self, names, info, data_name, &injected)?;
+                    module.section(&names);
+                    Ok(())
+                }
+                _ => reencode::utils::parse_custom_section(self, module, section),
+            }
+        }
+
+        fn parse_unknown_section(
+            &mut self,
+            module: &mut we::Module,
+            id: u8,
+            contents: &[u8],
+        ) -> Result<(), reencode::Error<Self::Error>> {
+            copy_unknown_section(self, module, id, contents)
+        }
+
+        fn intersperse_section_hook(
+            &mut self,
+            module: &mut we::Module,
+            after: Option<we::SectionId>,
+            before: Option<we::SectionId>,
+        ) -> Result<(), reencode::Error<Self::Error>> {
+            // Input module without imports still needs an import section for the unpacker
+            let past_imports = match before {
+                Some(we::SectionId::Custom) => false,
+                Some(id) => id as u8 > we::SectionId::Import as u8,
+                None => true,
+            };
+            if self.unpacker_import.is_some() && !self.imports_emitted && past_imports {
+                let mut imports = we::ImportSection::new();
+                self.import_unpacker(&mut imports);
+                module.section(&imports);
+            }
+            if after == Some(we::SectionId::Export) && self.info.start_fn_idx.is_none() {
+                module.section(&we::StartSection {
+                    function_index: self.new_start_fn_idx,
+                });
+            }
+            Ok(())
+        }
+    }
+
+    impl<'a> Merger<'a> {
+        /// Names of the unpacker's functions and the injected start function, by index
+        fn injected_function_names(&self) -> Vec<(u32, String)> {
+            let mut names = Vec::new();
+            if self.unpacker_import.is_some() {
+                names.push((self.unpack_fn_idx, "__wasm_squeeze_unpack".to_owned()));
+            } else {
+                for unpacker_idx in 0..self.index_spaces.unpacker_function_count {
+                    let idx = self.index_spaces.unpacker_function(unpacker_idx);
+                    let name = match idx == self.unpack_fn_idx {
+                        true => "__wasm_squeeze_unpack".to_owned(),
+                        false => format!("__wasm_squeeze_unpacker_{unpacker_idx}"),
+                    };
+                    names.push((idx, name));
+                }
+            }
+            if self.info.start_fn_idx.is_none() {
+                names.push((self.new_start_fn_idx, "__wasm_squeeze_start".to_owned()));
+            }
+            names
+        }
+
+        fn import_unpacker(&mut self, imports: &mut we::ImportSection) {
+            self.imports_emitted = true;
+            if let Some((module, field)) = self.unpacker_import {
+                let ty = we::EntityType::Function(self.index_spaces.unpack_fn_type());
+                imports.import(module, field, ty);
+            }
+        }
+
+        fn encode_prefix_instrs(
+            &mut self,
+            func: &mut we::Function,
+        ) -> Result<(), reencode::Error<SqueezeError>> {
+            for instr in self.prefix_instrs().map_err(reencode::Error::UserError)? {
+                func.instruction(&instr);
+            }
+            Ok(())
+        }
+
+        fn prefix_instrs(&self) -> Result<Vec<we::Instruction<'static>>, SqueezeError> {
+            use we::Instruction as I;
+
+            let packed = &self.packed_data;
+            let layout = &packed.layout;
+            let mut instrs = Vec::new();
+
+            let range_len = |range: &Range<i32>| {
+                i32::try_from(range.len()).map_err(|_| {
+                    SqueezeError::InternalInvariant(format!("layout range {range:?} is too long"))
+                })
+            };
+
+            // Layout address relative to the base global, if there is one
+            let address = |offset: i32| match self.info.data_base {
+                None => vec![I::I32Const(offset)],
+                Some(global) => vec![I::GlobalGet(global), I::I32Const(offset), I::I32Add],
+            };
+
+            for cluster in &layout.clusters {
+                instrs.extend(address(layout.context.start));
+                instrs.extend(address(cluster.staging.start));
+                instrs.extend(address(cluster.compressed.start));
+                instrs.extend([I::Call(self.unpack_fn_idx), I::Drop]);
+            }
+
+            // Global values are read before the copy might overwrite them
+            if packed.globals_restored {
+                let cluster = &layout.clusters[0];
+                let mut offset = cluster.staging.start + cluster.destination.len() as i32;
+                for &(global, value) in &self.info.mutable_globals {
+                    instrs.extend([I::I32Const(offset), value.load(), I::GlobalSet(global)]);
+                    offset += value.to_le_bytes().len() as i32;
+                }
+            }
+
+            for cluster in &layout.clusters {
+                // Data ending at the top of memory is decompressed right into its place
+                if cluster.destination.start != cluster.staging.start {
+                    instrs.extend(address(cluster.destination.start));
+                    instrs.extend(address(cluster.staging.start));
+                    instrs.extend([
+                        I::I32Const(range_len(&cluster.destination)?),
+                        I::MemoryCopy {
+                            src_mem: 0,
+                            dst_mem: 0,
+                        },
+                    ]);
+                }
+            }
+
+            for fill in &layout.fills {
+                instrs.extend(address(fill.start));
+                instrs.extend([
+                    I::I32Const(0),
+                    I::I32Const(range_len(fill)?),
+                    I::MemoryFill(0),
+                ]);
+            }
+
+            for register in self.target.profile().registers {
+                // Natural alignment, unless the register's offset is less aligned
+                let align = register
+                    .value
+                    .width()
+                    .trailing_zeros()
+                    .min(register.offset.trailing_zeros());
+                let memarg = we::MemArg {
+                    offset: 0,
+                    align,
+                    memory_index: 0,
+                };
+                instrs.push(I::I32Const(register.offset));
+                instrs.extend(match register.value {
+                    RegisterValue::I16(value) => [I::I32Const(value.into()), I::I32Store16(memarg)],
+                    RegisterValue::I32(value) => [I::I32Const(value), I::I32Store(memarg)],
+                    RegisterValue::I64(value) => [I::I64Const(value), I::I64Store(memarg)],
+                });
+            }
+
+            Ok(instrs)
+        }
+    }
+}
+
+/// Reencode the input module with merged uncompressed data and without the unpacker
+fn reencode_merged(
+    input_module: &[u8],
+    info: &RelevantInfo,
+    options: &SqueezeOptions,
+) -> anyhow::Result<we::Module> {
+    let mut module = we::Module::new();
+    MergeOnly {
+        info,
+        data_emitted: false,
+        renames: &options.renames,
+        options,
+    }
+    .parse_core_module(&mut module, wp::Parser::new(0), input_module)
+    .map_err(|err| match err {
+        reencode::Error::UserError(err) => anyhow::Error::from(err),
+        err => anyhow::Error::from(err),
+    })
+    .context("reencoding the input module with merged data")?;
+    return Ok(module);
+
+    struct MergeOnly<'a> {
+        info: &'a RelevantInfo,
+        data_emitted: bool,
+        renames: &'a Renames,
+        options: &'a SqueezeOptions,
+    }
+
+    impl Reencode for MergeOnly<'_> {
+        type Error = SqueezeError;
+
+        fn parse_import_section(
+            &mut self,
+            imports: &mut we::ImportSection,
+            section: wp::ImportSectionReader<'_>,
+        ) -> Result<(), reencode::Error<Self::Error>> {
+            let renames = self.renames;
+            parse_renamed_imports(self, renames, imports, section)
+        }
+
+        fn parse_export_section(
+            &mut self,
+            exports: &mut we::ExportSection,
+            section: wp::ExportSectionReader<'_>,
+        ) -> Result<(), reencode::Error<Self::Error>> {
+            let renames = self.renames;
+            parse_renamed_exports(self, renames, exports, section)
+        }
+
+        fn parse_data_section(
+            &mut self,
+            data: &mut we::DataSection,
+            section: wp::DataSectionReader<'_>,
+        ) -> Result<(), reencode::Error<Self::Error>> {
+            if !std::mem::replace(&mut self.data_emitted, true) {
+                self.info
+                    .data
+                    .encode_active(data, self.info.data_base)
+                    .map_err(reencode::Error::UserError)?;
+            }
+            parse_passive_data(data, section)
+        }
+
+        fn data_index(&mut self, data: u32) -> u32 {
+            self.info.data_index(data)
+        }
+
+        fn data_count(&mut self, _count: u32) -> u32 {
+            self.info.data_count()
+        }
+
+        fn parse_custom_section(
+            &mut self,
+            module: &mut we::Module,
+            section: wp::CustomSectionReader<'_>,
+        ) -> Result<(), reencode::Error<Self::Error>> {
+            if self.options.strips(section.name()) {
+                return Ok(());
+            }
+            match section.as_known() {
+                wp::KnownCustom::Name(names) => {
+                    let info = self.info;
+                    let names = remap_names(self, names, info, "__wasm_squeeze_merged", &[])?;
+                    module.section(&names);
+                    Ok(())
+                }
+                _ => reencode::utils::parse_custom_section(self, module, section),
+            }
+        }
+
+        fn parse_unknown_section(
+            &mut self,
+            module: &mut we::Module,
+            id: u8,
+            contents: &[u8],
+        ) -> Result<(), reencode::Error<Self::Error>> {
+            copy_unknown_section(self, module, id, contents)
+        }
+    }
+}
+
+/// Copy a section unknown to the parser verbatim, as it may come from a proposal newer than this
+/// tool, warning that indices in it are not remapped
+fn copy_unknown_section<T: Reencode + ?Sized>(
+    reencoder: &mut T,
+    module: &mut we::Module,
+    id: u8,
+    contents: &[u8],
+) -> Result<(), reencode::Error<T::Error>> {
+    log::warn!("Copying unknown section {id} as is, indices in it are not remapped");
+    reencode::utils::parse_unknown_section(reencoder, module, id, contents)
+}
+
+/// Name section with function and data indices remapped by the `reencoder`, naming the merged
+/// data segment `data_name` and adding the `injected` functions' names
+fn remap_names<T: Reencode + ?Sized>(
+    reencoder: &mut T,
+    section: wp::NameSectionReader<'_>,
+    info: &RelevantInfo,
+    data_name: &str,
+    injected: &[(u32, String)],
+) -> Result<we::NameSection, reencode::Error<T::Error>> {
+    let mut names = we::NameSection::new();
+    let mut functions_named = false;
+    for subsection in section {
+        let subsection = subsection?;
+        // Subsections are ordered by id, functions come right after the module name
+        let past_functions = !matches!(subsection, wp::Name::Module { .. } | wp::Name::Function(_));
+        if past_functions && !std::mem::replace(&mut functions_named, true) && !injected.is_empty()
+        {
+            names.functions(&name_map(injected.to_vec()));
+        }
+        match subsection {
+            wp::Name::Module { name, .. } => names.module(name),
+            wp::Name::Function(map) => {
+                functions_named = true;
+                let mut functions = injected.to_vec();
+                for naming in map {
+                    let naming = naming?;
+                    functions.push((
+                        reencoder.function_index(naming.index),
+                        naming.name.to_owned(),
+                    ));
+                }
+                names.functions(&name_map(functions));
+            }
+            wp::Name::Local(map) => names.locals(&remap_indirect_names(reencoder, map)?),
+            wp::Name::Label(map) => names.labels(&remap_indirect_names(reencoder, map)?),
+            wp::Name::Data(map) => {
+                // Active segments are all merged into the first one
+                let mut data = vec![(0, data_name.to_owned())];
+                for naming in map {
+                    let naming = naming?;
+                    if info.passive_data.binary_search(&naming.index).is_ok() {
+                        data.push((info.data_index(naming.index), naming.name.to_owned()));
+                    }
+                }
+                names.data(&name_map(data));
+            }
+            wp::Name::Type(map) => names.types(&copy_names(map)?),
+            wp::Name::Table(map) => names.tables(&copy_names(map)?),
+            wp::Name::Memory(map) => names.memories(&copy_names(map)?),
+            wp::Name::Global(map) => names.globals(&copy_names(map)?),
+            wp::Name::Element(map) => names.elements(&copy_names(map)?),
+            wp::Name::Field(map) => names.fields(&copy_indirect_names(map)?),
+            wp::Name::Tag(map) => names.tags(&copy_names(map)?),
+            wp::Name::Unknown { ty, data, .. } => names.raw(ty, data),
+        }
+    }
+    if !functions_named && !injected.is_empty() {
+        names.functions(&name_map(injected.to_vec()));
+    }
+    Ok(names)
+}
+
+/// Name map of the `names`, sorted by index
+fn name_map(mut names: Vec<(u32, String)>) -> we::NameMap {
+    names.sort_by_key(|&(idx, _)| idx);
+    let mut map = we::NameMap::new();
+    for (idx, name) in &names {
+        map.append(*idx, name);
+    }
+    map
+}
+
+fn copy_names(names: wp::NameMap<'_>) -> wp::Result<we::NameMap> {
+    let mut map = we::NameMap::new();
+    for naming in names {
+        let naming = naming?;
+        map.append(naming.index, naming.name);
+    }
+    Ok(map)
+}
+
+fn copy_indirect_names(names: wp::IndirectNameMap<'_>) -> wp::Result<we::IndirectNameMap> {
+    let mut map = we::IndirectNameMap::new();
+    for naming in names {
+        let naming = naming?;
+        map.append(naming.index, &copy_names(naming.names)?);
+    }
+    Ok(map)
+}
+
+/// Copy of the `names` of functions' locals or labels with function indices remapped
+fn remap_indirect_names<T: Reencode + ?Sized>(
+    reencoder: &mut T,
+    names: wp::IndirectNameMap<'_>,
+) -> Result<we::IndirectNameMap, reencode::Error<T::Error>> {
+    let mut map = we::IndirectNameMap::new();
+    for naming in names {
+        let naming = naming?;
+        map.append(
+            reencoder.function_index(naming.index),
+            &copy_names(naming.names)?,
+        );
+    }
+    Ok(map)
+}
+
+/// Reencoder dropping custom sections only
+struct Stripper<'a>(&'a SqueezeOptions);
+
+impl Reencode for Stripper<'_> {
+    type Error = std::convert::Infallible;
+
+    fn parse_custom_section(
+        &mut self,
+        module: &mut we::Module,
+        section: wp::CustomSectionReader<'_>,
+    ) -> Result<(), reencode::Error<Self::Error>> {
+        match self.0.strips(section.name()) {
+            true => Ok(()),
+            false => reencode::utils::parse_custom_section(self, module, section),
+        }
+    }
+}
+
+/// Copy passive segments of the `section`, dropping active ones which are merged
+fn parse_passive_data<E>(
+    data: &mut we::DataSection,
+    section: wp::DataSectionReader<'_>,
+) -> Result<(), reencode::Error<E>> {
+    for segment in section {
+        let segment = segment?;
+        if let wp::DataKind::Passive = segment.kind {
+            data.passive(segment.data.iter().copied());
+        }
+    }
+    Ok(())
+}
+
+fn parse_renamed_imports<T: Reencode + ?Sized>(
+    reencoder: &mut T,
+    renames: &Renames,
+    imports: &mut we::ImportSection,
+    section: wp::ImportSectionReader<'_>,
+) -> Result<(), reencode::Error<T::Error>> {
+    for import in section {
+        let import = import?;
+        let ty = reencoder.entity_type(import.ty)?;
+        let (module, name) = renames.import(import.module, import.name);
+        imports.import(module, name, ty);
+    }
+    Ok(())
+}
+
+fn parse_renamed_exports<T: Reencode + ?Sized>(
+    reencoder: &mut T,
+    renames: &Renames,
+    exports: &mut we::ExportSection,
+    section: wp::ExportSectionReader<'_>,
+) -> Result<(), reencode::Error<T::Error>> {
+    for export in section {
+        let export = export?;
+        let kind = reencoder.export_kind(export.kind);
+        let index = reencoder.external_index(export.kind, export.index);
+        exports.export(renames.export(export.name), kind, index);
+    }
+    Ok(())
+}
+
+/// Id of the `dylink.0` subsection holding the memory and table sizes
+const DYLINK_MEM_INFO: u8 = 1;
+
+/// Memory size of the `dylink.0` custom `section`, if it has one
+fn dylink_memory_size(
+    section: &wp::CustomSectionReader<'_>,
+) -> Result<Option<u32>, wp::BinaryReaderError> {
+    let mut reader = wp::BinaryReader::new(section.data(), section.data_offset(), WASM_FEATURES);
+    while !reader.eof() {
+        let id = reader.read_u8()?;
+        let len = reader.read_var_u32()?;
+        let payload = reader.read_bytes(len as usize)?;
+        if id == DYLINK_MEM_INFO {
+            let mut payload = wp::BinaryReader::new(payload, 0, WASM_FEATURES);
+            return Ok(Some(payload.read_var_u32()?));
+        }
+    }
+    Ok(None)
+}
+
+/// Contents of the `dylink.0` custom `section` with its memory size grown to at least
+/// `memory_size` bytes, keeping the other subsections as is
+fn grow_dylink_memory_size(
+    section: &wp::CustomSectionReader<'_>,
+    memory_size: u32,
+) -> Result<Vec<u8>, wp::BinaryReaderError> {
+    let mut reader = wp::BinaryReader::new(section.data(), section.data_offset(), WASM_FEATURES);
+    let mut bytes = Vec::new();
+    while !reader.eof() {
+        let id = reader.read_u8()?;
+        let len = reader.read_var_u32()?;
+        let payload = reader.read_bytes(len as usize)?;
+        bytes.push(id);
+        if id != DYLINK_MEM_INFO {
+            len.encode(&mut bytes);
+            bytes.extend(payload);
+            continue;
+        }
+        let mut payload = wp::BinaryReader::new(payload, 0, WASM_FEATURES);
+        let mut mem_info = Vec::new();
+        let old_size = payload.read_var_u32()?;
+        old_size.max(memory_size).encode(&mut mem_info);
+        // Memory alignment and the table size and alignment follow
+        for _ in 0..3 {
+            payload.read_var_u32()?.encode(&mut mem_info);
+        }
+        (mem_info.len() as u32).encode(&mut bytes);
+        bytes.extend(mem_info);
+    }
+    Ok(bytes)
+}
+
+/// Format prologue instructions in the text format, one instruction per line
+fn prologue_wat(instrs: &[we::Instruction]) -> String {
+    use we::Instruction as I;
+
+    let mut wat = String::from(";; wasm-squeeze decompression prologue\n");
+    for instr in instrs {
+        let line = match instr {
+            I::I32Const(value) => format!("i32.const {value}"),
+            I::I64Const(value) => format!("i64.const {value:#x}"),
+            I::Call(func) => format!("call {func} ;; upkr_unpack"),
+            I::Drop => "drop".to_owned(),
+            I::MemoryCopy { .. } => "memory.copy".to_owned(),
+            I::MemoryFill(_) => "memory.fill".to_owned(),
+            I::I64Store(memarg) => format!("i64.store align={}", 1 << memarg.align),
+            I::I32Store(memarg) => format!("i32.store align={}", 1 << memarg.align),
+            I::I32Store16(memarg) => format!("i32.store16 align={}", 1 << memarg.align),
+            I::GlobalGet(global) => format!("global.get {global} ;; data base"),
+            I::GlobalSet(global) => format!("global.set {global}"),
+            I::I32Load(_) => "i32.load align=1".to_owned(),
+            I::I64Load(_) => "i64.load align=1".to_owned(),
+            I::F32Load(_) => "f32.load align=1".to_owned(),
+            I::F64Load(_) => "f64.load align=1".to_owned(),
+            I::I32Add => "i32.add".to_owned(),
+            I::I32Sub => "i32.sub".to_owned(),
+            // Rendered as is rather than failing to print a valid prologue
+            other => format!(";; {other:?}"),
+        };
+        wat.push_str(&line);
+        wat.push('\n');
+    }
+    wat
+}
+
+struct PackedData {
+    data: Vec<u8>,
+    layout: LayoutPlan,
+    /// Values of mutable globals follow the data of the single cluster in its staging area
+    globals_restored: bool,
+}
+
+/// Approximate size of the prologue code for each additional cluster
+const CLUSTER_PROLOGUE_LEN: usize = 24;
+
+/// Compress clusters of data separated by zero gaps of at least `min_gap` bytes separately,
+/// returning `None` unless that beats `whole_packed_len` bytes of data compressed as a whole
+fn pack_split(
+    info: &RelevantInfo,
+    min_gap: usize,
+    whole_packed_len: usize,
+    options: &SqueezeOptions,
+) -> anyhow::Result<Option<PackedData>> {
+    let clusters = info.data.split_at_gaps(min_gap);
+    // Clusters are only supported at known addresses
+    if clusters.len() < 2 || info.data_base.is_some() {
+        return Ok(None);
+    }
+    let Some(packed) = pack_clusters(&clusters, info.memory_size, options)? else {
+        return Ok(None);
+    };
+    let split_len = packed.data.len() + CLUSTER_PROLOGUE_LEN * (clusters.len() - 1);
+    log::info!(
+        "Compressing {} clusters separately takes about {split_len} bytes instead of {whole_packed_len}",
+        clusters.len()
+    );
+    Ok((split_len < whole_packed_len).then_some(packed))
+}
+
+/// Compress each of the `clusters` of data separately, or return `None` if they do not fit into
+/// the memory
+fn pack_clusters(
+    clusters: &[Data<Vec<u8>>],
+    memory_size: i32,
+    options: &SqueezeOptions,
+) -> anyhow::Result<Option<PackedData>> {
+    let mut data = Vec::new();
+    let mut packed_lens = Vec::with_capacity(clusters.len());
+    let mut destinations = Vec::with_capacity(clusters.len());
+    for cluster in clusters {
+        let packed = options.pack(&cluster.data)?;
+        packed_lens.push(packed.len());
+        data.extend(packed);
+        let Ok(destination) = cluster.range() else {
+            return Ok(None);
+        };
+        destinations.push(destination);
+    }
+    let layout = LayoutPlan::split(&destinations, &packed_lens, memory_size)
+        .and_then(|layout| layout.reserve(&options.reserved_ranges()));
+    let Some(layout) = layout else {
+        return Ok(None);
+    };
+    Ok(Some(PackedData {
+        layout,
+        data,
+        globals_restored: false,
+    }))
+}
+
+/// Plan memory layout for the `packed` data, which decompresses into the data followed by
+/// `tail_len` bytes not to be copied, or return `None` if it does not fit into the memory
+fn plan_layout(
+    info: &RelevantInfo,
+    packed: &[u8],
+    options: &SqueezeOptions,
+    tail_len: usize,
+) -> Option<LayoutPlan> {
+    // Data above 2GiB does not fit the memory as it is capped
+    let destination = info.data.range().ok()?;
+    if info.data_base.is_some() {
+        // Reserved ranges are absolute, while the whole layout moves with the base
+        if !options.reserved_ranges().is_empty() {
+            return None;
+        }
+        // Memory past the module's reserved size belongs to the heap or other modules
+        if info.dylink_memory_size.is_none() {
+            log::warn!("Data is relative to a base, but no dylink.0 section reserves memory there");
+            return None;
+        }
+        return LayoutPlan::relative(destination, packed.len(), tail_len)
+            .filter(|layout| layout.end() <= info.memory_size);
+    }
+    if options.in_place && tail_len == 0 {
+        let layout = upkr::calculate_margin(packed, &options.packer.upkr())
+            .ok()
+            .and_then(|margin| {
+                LayoutPlan::in_place(destination.clone(), packed.len(), margin, info.memory_size)
+            })
+            .and_then(|layout| layout.reserve(&options.reserved_ranges()));
+        if layout.is_some() {
+            return layout;
+        }
+        log::warn!("Could not fit data for decompression in place, using the staging area");
+    }
+    LayoutPlan::new(
+        destination,
+        packed.len(),
+        options.context_in_data,
+        info.memory_size,
+        tail_len,
+    )?
+    .reserve(&options.reserved_ranges())
+}
+
+/// Memory layout used by the decompression prologue
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct LayoutPlan {
+    /// Unpacker context
+    context: Range<i32>,
+    /// Separately compressed data, ordered by address. Compressed data of all clusters is placed
+    /// by a single data segment.
+    clusters: Vec<ClusterLayout>,
+    /// Non-empty areas zeroed after the copy, which is everything except the destinations
+    fills: Vec<Range<i32>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ClusterLayout {
+    /// Compressed data placed by the data segment
+    compressed: Range<i32>,
+    /// Where the unpacker writes decompressed data, at the top of the memory or at the destination
+    /// for decompression in place
+    staging: Range<i32>,
+    /// Original data location, decompressed data is copied here from the staging area
+    destination: Range<i32>,
+}
+
+impl LayoutPlan {
+    /// Plan layout for the data at `destination` compressed into `packed_len` bytes, or return
+    /// `None` if it does not fit into the memory of `memory_size` bytes. Decompressed data is
+    /// followed by `tail_len` bytes, which stay in the staging area.
+    fn new(
+        destination: Range<i32>,
+        packed_len: usize,
+        context_in_data: bool,
+        memory_size: i32,
+        tail_len: usize,
+    ) -> Option<Self> {
+        let data_len = destination.end.checked_sub(destination.start)?;
+        let packed_len = i32::try_from(packed_len).ok()?;
+        let staging_len = data_len.checked_add(tail_len.try_into().ok()?)?;
+        let staging = memory_size.checked_sub(staging_len)?..memory_size;
+        if !(0 <= destination.start && destination.end <= memory_size) {
+            return None;
+        }
+
+        let context_in_data = context_in_data
+            .then(|| Self::context_in_data(&destination, &staging, packed_len))
+            .flatten();
+        let (context_offset, compressed_offset) = match context_in_data {
+            Some(context_offset) => (context_offset, 0),
+            None => (CONTEXT_OFFSET, COMPRESSED_DATA_OFFSET),
+        };
+        let compressed = compressed_offset..compressed_offset.checked_add(packed_len)?;
+        if compressed.end > staging.start {
+            return None;
+        }
+
+        Some(LayoutPlan {
+            context: context_offset..context_offset + common::CONTEXT_SIZE,
+            fills: merge_ranges([0..destination.start, destination.end..memory_size]),
+            clusters: vec![ClusterLayout {
+                compressed,
+                staging,
+                destination,
+            }],
+        })
+    }
+
+    /// Plan layout for the data at `destination` relative to a base address only known at
+    /// runtime, such as `__memory_base`. Compressed data is placed at the destination, followed
+    /// by the context and the staging area, which the module's `dylink.0` memory size has to be
+    /// grown to cover. Only that scratch area is zeroed afterwards.
+    fn relative(destination: Range<i32>, packed_len: usize, tail_len: usize) -> Option<Self> {
+        let data_len = destination.end.checked_sub(destination.start)?;
+        let packed_len = i32::try_from(packed_len).ok()?;
+        let staging_len = data_len.checked_add(tail_len.try_into().ok()?)?;
+        if destination.start < 0 {
+            return None;
+        }
+
+        let compressed = destination.start..destination.start.checked_add(packed_len)?;
+        // Context has alignment of 4
+        let context_start = compressed.end.checked_add(3)? & !3;
+        let context = context_start..context_start.checked_add(common::CONTEXT_SIZE)?;
+        let staging = context.end..context.end.checked_add(staging_len)?;
+        Some(LayoutPlan {
+            context,
+            fills: merge_ranges([destination.end..staging.end]),
+            clusters: vec![ClusterLayout {
+                compressed,
+                staging,
+                destination,
+            }],
+        })
+    }
+
+    /// Address past everything the layout uses
+    fn end(&self) -> i32 {
+        let cluster_ends = self.clusters.iter().flat_map(|cluster| {
+            [
+                cluster.compressed.end,
+                cluster.staging.end,
+                cluster.destination.end,
+            ]
+        });
+        cluster_ends.fold(self.context.end, i32::max)
+    }
+
+    /// Plan layout for decompressing right into the `destination`, with compressed data ending
+    /// `margin` bytes past it so the unpacker never overwrites bytes it has yet to read
+    fn in_place(
+        destination: Range<i32>,
+        packed_len: usize,
+        margin: isize,
+        memory_size: i32,
+    ) -> Option<Self> {
+        let packed_len = i32::try_from(packed_len).ok()?;
+        let compressed_end = destination.end.checked_add(margin.try_into().ok()?)?;
+        let compressed = compressed_end.checked_sub(packed_len)?..compressed_end;
+        let context = CONTEXT_OFFSET..CONTEXT_OFFSET + common::CONTEXT_SIZE;
+        if !(context.end <= destination.start.min(compressed.start)
+            && destination.end.max(compressed.end) <= memory_size)
+        {
+            return None;
+        }
+
+        Some(LayoutPlan {
+            context,
+            fills: merge_ranges([0..destination.start, destination.end..memory_size]),
+            clusters: vec![ClusterLayout {
+                compressed,
+                staging: destination.clone(),
+                destination,
+            }],
+        })
+    }
+
+    /// Plan layout for clusters of data at `destinations`, ordered by address and compressed
+    /// into `packed_lens` bytes each. Clusters are decompressed into adjacent staging areas at the
+    /// top of the memory, each no lower than its destination, so copying them in order never
+    /// overwrites a staging area yet to be copied.
+    fn split(destinations: &[Range<i32>], packed_lens: &[usize], memory_size: i32) -> Option<Self> {
+        let data_len = destinations.iter().try_fold(0i32, |len, destination| {
+            len.checked_add(destination.len().try_into().ok()?)
+        })?;
+        let mut staging_start = memory_size.checked_sub(data_len)?;
+        let mut compressed_start = COMPRESSED_DATA_OFFSET;
+        let mut clusters = Vec::with_capacity(destinations.len());
+        let mut fills = Vec::with_capacity(destinations.len() + 1);
+        let mut fill_start = 0;
+        for (destination, &packed_len) in iter::zip(destinations, packed_lens) {
+            if !(fill_start <= destination.start && destination.end <= memory_size) {
+                return None;
+            }
+            fills.push(fill_start..destination.start);
+            fill_start = destination.end;
+
+            let packed_len = i32::try_from(packed_len).ok()?;
+            let compressed = compressed_start..compressed_start.checked_add(packed_len)?;
+            let staging = staging_start..staging_start + destination.end - destination.start;
+            compressed_start = compressed.end;
+            staging_start = staging.end;
+            clusters.push(ClusterLayout {
+                compressed,
+                staging,
+                destination: destination.clone(),
+            });
+        }
+        fills.push(fill_start..memory_size);
+        if compressed_start > memory_size - data_len {
+            return None;
+        }
+
+        Some(LayoutPlan {
+            context: CONTEXT_OFFSET..CONTEXT_OFFSET + common::CONTEXT_SIZE,
+            clusters,
+            fills: merge_ranges(fills),
+        })
+    }
+
+    /// Leave the `reserved` ranges out of the fills, or return `None` if the context, compressed
+    /// data or staging areas overlap them
+    fn reserve(mut self, reserved: &[Range<i32>]) -> Option<Self> {
+        let overlaps = |range: &Range<i32>| {
+            reserved
+                .iter()
+                .any(|reserved| reserved.start < range.end && range.start < reserved.end)
+        };
+        // Staging area at the destination is the data's own place
+        let staging = self
+            .clusters
+            .iter()
+            .filter(|cluster| cluster.staging != cluster.destination)
+            .map(|cluster| &cluster.staging);
+        let compressed = self.clusters.iter().map(|cluster| &cluster.compressed);
+        if iter::once(&self.context)
+            .chain(compressed)
+            .chain(staging)
+            .any(overlaps)
+        {
+            return None;
+        }
+
+        for reserved in reserved {
+            self.fills = self
+                .fills
+                .iter()
+                .flat_map(|fill| {
+                    [
+                        fill.start..fill.end.min(reserved.start),
+                        fill.start.max(reserved.end)..fill.end,
+                    ]
+                })
+                .filter(|fill| !fill.is_empty())
+                .collect();
+        }
+        Some(self)
+    }
+
+    /// Start of the compressed data of all clusters
+    fn compressed_start(&self) -> i32 {
+        self.clusters[0].compressed.start
+    }
+
+    /// Memory written by the data segment and the prologue, in order. Everything but registers
+    /// is relative to the data base global if there is one.
+    fn memory_writes(&self, target: Target) -> Vec<MemoryWrite> {
+        let write = |kind, range| MemoryWrite { kind, range };
+        let mut writes = Vec::new();
+        writes.extend(
+            self.clusters
+                .iter()
+                .map(|cluster| write(MemoryWriteKind::CompressedData, cluster.compressed.clone())),
+        );
+        writes.push(write(MemoryWriteKind::Context, self.context.clone()));
+        writes.extend(
+            self.clusters
+                .iter()
+                .map(|cluster| write(MemoryWriteKind::Staging, cluster.staging.clone())),
+        );
+        writes.extend(
+            self.clusters
+                .iter()
+                .filter(|cluster| cluster.destination != cluster.staging)
+                .map(|cluster| write(MemoryWriteKind::Destination, cluster.destination.clone())),
+        );
+        writes.extend(
+            self.fills
+                .iter()
+                .map(|fill| write(MemoryWriteKind::Fill, fill.clone())),
+        );
+        writes.extend(target.profile().registers.iter().map(|register| {
+            let range = register.offset..register.offset + register.value.width();
+            write(MemoryWriteKind::Register, range)
+        }));
+        writes
+    }
+
+    /// Find a spot for the unpacker context inside of the destination, assuming compressed data
+    /// is placed at 0 address. Destination is only overwritten by `memory.copy` after
+    /// decompression is done, so the context does not dirty any memory outside of it.
+    fn context_in_data(
+        destination: &Range<i32>,
+        staging: &Range<i32>,
+        packed_len: i32,
+    ) -> Option<i32> {
+        // Context has alignment of 4
+        let start = destination.start.max(packed_len).checked_add(3)? & !3;
+        let end = start.checked_add(common::CONTEXT_SIZE)?;
+        let found = end <= destination.end.min(staging.start);
+        if !found {
+            log::warn!(
+                "Could not fit unpacker context into the data region, placing it at {CONTEXT_OFFSET}"
+            );
+        }
+        found.then_some(start)
+    }
+}
+
+/// Import and export renames
+#[derive(Default, Clone)]
+pub struct Renames {
+    imports: HashMap<(String, String), (String, String)>,
+    exports: HashMap<String, String>,
+}
+
+impl Renames {
+    /// Parse the mapping, each line of which is either
+    /// `import <module> <name> <new module> <new name>` or `export <name> <new name>`
+    pub fn parse(mapping: &str) -> anyhow::Result<Self> {
+        let mut renames = Renames::default();
+        for (i, line) in mapping.lines().enumerate() {
+            let words: Vec<_> = line.split_whitespace().collect();
+            match words[..] {
+                [] => {}
+                ["import", module, name, new_module, new_name] => {
+                    renames.imports.insert(
+                        (module.to_owned(), name.to_owned()),
+                        (new_module.to_owned(), new_name.to_owned()),
+                    );
+                }
+                ["export", name, new_name] => {
+                    renames.exports.insert(name.to_owned(), new_name.to_owned());
+                }
+                _ => anyhow::bail!("invalid mapping on line {}: {line:?}", i + 1),
+            }
+        }
+        Ok(renames)
+    }
+
+    fn import<'a>(&'a self, module: &'a str, name: &'a str) -> (&'a str, &'a str) {
+        match self.imports.get(&(module.to_owned(), name.to_owned())) {
+            Some((module, name)) => (module.as_str(), name.as_str()),
+            None => (module, name),
+        }
+    }
+
+    fn export<'a>(&'a self, name: &'a str) -> &'a str {
+        self.exports.get(name).map_or(name, String::as_str)
+    }
+}
+
+/// Size of the string encoded inside of a wasm module
+fn string_encoded_len(s: &str) -> anyhow::Result<usize> {
+    let mut bytes = Vec::new();
+    u32::try_from(s.len())?.encode(&mut bytes);
+    Ok(bytes.len() + s.len())
+}
+
+/// Drop empty ranges and merge adjacent or overlapping ones
+fn merge_ranges(ranges: impl IntoIterator<Item = Range<i32>>) -> Vec<Range<i32>> {
+    let mut ranges: Vec<_> = ranges.into_iter().filter(|r| !r.is_empty()).collect();
+    ranges.sort_unstable_by_key(|r| r.start);
+    let mut merged: Vec<Range<i32>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+struct AdaptUnpacker {
+    functions_index_base: u32,
+    types_index_base: u32,
+}
+
+impl Reencode for AdaptUnpacker {
+    type Error = SqueezeError;
+
+    // Validated unpacker's indices stay within the bounds of `IndexSpaces::checked`
+
+    fn type_index(&mut self, ty: u32) -> u32 {
+        ty + self.types_index_base
+    }
+
+    fn function_index(&mut self, func: u32) -> u32 {
+        func + self.functions_index_base
+    }
+}
+
+/// Evaluate a data offset into an optional base global and an address relative to it
+fn eval_offset(
+    expr: &wp::ConstExpr,
+    globals: &[Option<i32>],
+) -> anyhow::Result<(Option<u32>, u64)> {
+    let (base, value) = eval_relative_i32(expr, globals)?;
+    // Memory addresses are unsigned
+    Ok((base, u64::from(value as u32)))
+}
+
+fn eval_i32(expr: &wp::ConstExpr, globals: &[Option<i32>]) -> anyhow::Result<i32> {
+    match eval_relative_i32(expr, globals)? {
+        (None, value) => Ok(value),
+        (Some(global), _) => anyhow::bail!("Global {global} is not a known constant"),
+    }
+}
+
+/// Evaluate an `i32` constant expression, including extended constant ones, with `globals`
+/// holding values of immutable globals where they are known. The value may be relative to a
+/// single global with an unknown value, which is returned along with it.
+fn eval_relative_i32(
+    expr: &wp::ConstExpr,
+    globals: &[Option<i32>],
+) -> anyhow::Result<(Option<u32>, i32)> {
+    let mut reader = expr.get_operators_reader();
+    let mut stack = Vec::new();
+    loop {
+        let value = match reader.read()? {
+            wp::Operator::I32Const { value } => (None, value),
+            wp::Operator::GlobalGet { global_index } => {
+                match globals.get(global_index as usize).copied().flatten() {
+                    Some(value) => (None, value),
+                    None => (Some(global_index), 0),
+                }
+            }
+            op @ (wp::Operator::I32Add | wp::Operator::I32Sub | wp::Operator::I32Mul) => {
+                let (Some(rhs), Some(lhs)) = (stack.pop(), stack.pop()) else {
+                    anyhow::bail!("Expression stack underflow");
+                };
+                match (op, lhs, rhs) {
+                    (wp::Operator::I32Add, (base, lhs), (None, rhs))
+                    | (wp::Operator::I32Add, (None, lhs), (base, rhs)) => {
+                        (base, lhs.wrapping_add(rhs))
+                    }
+                    (wp::Operator::I32Sub, (base, lhs), (None, rhs)) => {
+                        (base, lhs.wrapping_sub(rhs))
+                    }
+                    (wp::Operator::I32Mul, (None, lhs), (None, rhs)) => {
+                        (None, lhs.wrapping_mul(rhs))
+                    }
+                    _ => anyhow::bail!("Expression is not a constant offset from a single global"),
+                }
+            }
+            wp::Operator::End => break,
+            op => anyhow::bail!("Unsupported operator in a constant expression: {op:?}"),
+        };
+        stack.push(value);
+    }
+    match stack[..] {
+        [value] => Ok(value),
+        _ => anyhow::bail!("Expression leaves {} values instead of one", stack.len()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONTEXT: Range<i32> = CONTEXT_OFFSET..CONTEXT_OFFSET + common::CONTEXT_SIZE;
+
+    #[test]
+    fn reencoding_round_trips() {
+        let modules = [
+            // Exported memory and a function exported under several names
+            r#"(module $roundtrip
+                (memory $memory (export "memory") (export "mem") 1)
+                (func $add (export "add") (export "plus") (param $a i32) (param $b i32) (result i32)
+                    (local $sum i32)
+                    (local.set $sum (i32.add (local.get $a) (local.get $b)))
+                    (local.get $sum))
+                (data $greeting (i32.const 0x10) "hello"))"#,
+            // Imports, tables and globals shifting the index spaces
+            r#"(module
+                (import "env" "memory" (memory 1))
+                (import "env" "log" (func $log (param i32)))
+                (global $counter (export "counter") (mut i32) (i32.const 7))
+                (table $table (export "table") 2 funcref)
+                (elem (table $table) (i32.const 0) func $log $start)
+                (func $start (call $log (global.get $counter)))
+                (start $start)
+                (data (i32.const 0x100) "\00\01\02")
+                (data $passive "passive"))"#,
+        ];
+        let options = SqueezeOptions {
+            no_compress: true,
+            no_inject: true,
+            ..SqueezeOptions::default()
+        };
+        for module in modules {
+            let input = wat::parse_str(module).unwrap();
+            let outcome = squeeze_slice(&input, &options).unwrap();
+            validate_output(&outcome.output).unwrap();
+            if outcome.output != input {
+                assert_eq!(
+                    wasmprinter::print_bytes(&outcome.output).unwrap(),
+                    wasmprinter::print_bytes(&input).unwrap()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn layout_of_data_at_zero() {
+        let layout = LayoutPlan::new(0..0x100, 0x40, false, DEFAULT_MEM_SIZE, 0).unwrap();
+        assert_eq!(layout.context, CONTEXT);
+        assert_eq!(
+            layout.clusters,
+            [ClusterLayout {
+                compressed: COMPRESSED_DATA_OFFSET..COMPRESSED_DATA_OFFSET + 0x40,
+                staging: 0xff00..0x10000,
+                destination: 0..0x100,
+            }]
+        );
+        assert_eq!(layout.fills, [0x100..0x10000]);
+
+        // Context and compressed data would be overwritten before being read
+        assert_eq!(
+            LayoutPlan::in_place(0..0x100, 0x40, 1, DEFAULT_MEM_SIZE),
+            None
+        );
+
+        let layout = LayoutPlan::split(&[0..0x100, 0x8000..0x8100], &[0x20, 0x30], 0x10000);
+        let layout = layout.unwrap();
+        let compressed = COMPRESSED_DATA_OFFSET..COMPRESSED_DATA_OFFSET + 0x20;
+        assert_eq!(
+            layout.clusters,
+            [
+                ClusterLayout {
+                    compressed: compressed.clone(),
+                    staging: 0xfe00..0xff00,
+                    destination: 0..0x100,
+                },
+                ClusterLayout {
+                    compressed: compressed.end..compressed.end + 0x30,
+                    staging: 0xff00..0x10000,
+                    destination: 0x8000..0x8100,
+                },
+            ]
+        );
+        assert_eq!(layout.fills, [0x100..0x8000, 0x8100..0x10000]);
+    }
+
+    #[test]
+    fn layout_of_data_ending_at_the_top() {
+        // Staging area is the destination itself, so nothing is copied
+        let layout = LayoutPlan::new(0xf000..0x10000, 0x40, false, DEFAULT_MEM_SIZE, 0).unwrap();
+        assert_eq!(layout.clusters[0].staging, 0xf000..0x10000);
+        assert_eq!(layout.fills, [0..0xf000]);
+        assert_eq!(layout.memory_writes(Target::Generic).len(), 4);
+
+        // Margin past the end of memory
+        assert_eq!(
+            LayoutPlan::in_place(0xf000..0x10000, 0x40, 4, DEFAULT_MEM_SIZE),
+            None
+        );
+        let layout = LayoutPlan::in_place(0xf000..0x10000, 0x40, 0, DEFAULT_MEM_SIZE).unwrap();
+        assert_eq!(layout.clusters[0].compressed, 0xffc0..0x10000);
+        assert_eq!(layout.fills, [0..0xf000]);
+
+        let destinations = [0x1000..0x1100, 0xff00..0x10000];
+        let layout = LayoutPlan::split(&destinations, &[0x20, 0x20], DEFAULT_MEM_SIZE).unwrap();
+        assert_eq!(layout.clusters[0].staging, 0xfe00..0xff00);
+        assert_eq!(layout.clusters[1].staging, 0xff00..0x10000);
+        assert_eq!(layout.fills, [0..0x1000, 0x1100..0xff00]);
+    }
+
+    #[test]
+    fn layout_of_tiny_data() {
+        let layout = LayoutPlan::new(0x2000..0x2001, 3, false, DEFAULT_MEM_SIZE, 0).unwrap();
+        assert_eq!(layout.clusters[0].staging, 0xffff..0x10000);
+        assert_eq!(layout.fills, [0..0x2000, 0x2001..0x10000]);
+
+        let layout = LayoutPlan::in_place(0x2000..0x2001, 3, 2, DEFAULT_MEM_SIZE).unwrap();
+        assert_eq!(layout.clusters[0].compressed, 0x2000..0x2003);
+        assert_eq!(layout.clusters[0].staging, 0x2000..0x2001);
+
+        let destinations = [0x2000..0x2001, 0x3000..0x3001];
+        let layout = LayoutPlan::split(&destinations, &[3, 3], DEFAULT_MEM_SIZE).unwrap();
+        assert_eq!(layout.clusters[0].staging, 0xfffe..0xffff);
+        assert_eq!(layout.clusters[1].staging, 0xffff..0x10000);
+        assert_eq!(layout.fills, [0..0x2000, 0x2001..0x3000, 0x3001..0x10000]);
+    }
+
+    #[test]
+    fn layout_of_near_full_memory() {
+        // Staging area leaves no room for the compressed data
+        assert_eq!(
+            LayoutPlan::new(0..0xfd00, 0x1000, false, DEFAULT_MEM_SIZE, 0),
+            None
+        );
+        assert!(LayoutPlan::new(0..0xfd00, 0x40, false, DEFAULT_MEM_SIZE, 0).is_some());
+
+        let destination = COMPRESSED_DATA_OFFSET..DEFAULT_MEM_SIZE;
+        assert_eq!(
+            LayoutPlan::in_place(destination.clone(), 0x1000, 1, DEFAULT_MEM_SIZE),
+            None
+        );
+        let layout = LayoutPlan::in_place(destination, 0x1000, 0, DEFAULT_MEM_SIZE).unwrap();
+        assert_eq!(layout.fills, [0..COMPRESSED_DATA_OFFSET]);
+
+        let destinations = [0..0x8000, 0x8000..0xff00];
+        assert_eq!(
+            LayoutPlan::split(&destinations, &[0x1000, 0x1000], DEFAULT_MEM_SIZE),
+            None
+        );
+    }
+
+    #[test]
+    fn layout_of_data_near_2gib() {
+        let data = |offset: u64, len: usize| Data {
+            offset,
+            data: vec![0; len],
+        };
+        assert_eq!(
+            data(0x7fff_f2ff, 0xd00).range().unwrap(),
+            0x7fff_f2ff..i32::MAX
+        );
+        assert!(data(0x7fff_f800, 0xd00).range().is_err());
+        assert!(data(0x8000_0000, 1).range().is_err());
+
+        let destination = 0x7fff_f2ff..i32::MAX;
+        // Data ending at the top of the memory is its own staging area
+        let layout = LayoutPlan::new(destination.clone(), 0x40, false, i32::MAX, 0).unwrap();
+        assert_eq!(layout.clusters[0].staging, destination);
+        // Memory of whole pages ends below the data
+        let memory_size = i32::MAX & !(PAGE_SIZE as i32 - 1);
+        assert_eq!(
+            LayoutPlan::new(destination.clone(), 0x40, false, memory_size, 0),
+            None
+        );
+
+        // Areas past the data would cross 2GiB
+        let tail_len = i32::MAX as usize;
+        assert_eq!(
+            LayoutPlan::new(destination.clone(), 0x40, false, i32::MAX, tail_len),
+            None
+        );
+        assert_eq!(
+            LayoutPlan::in_place(destination.clone(), 0x40, 1, i32::MAX),
+            None
+        );
+        assert_eq!(LayoutPlan::relative(destination.clone(), 0x40, 0), None);
+        assert_eq!(
+            LayoutPlan::split(&[0..0x7fff_0000, destination], &[0x40, 0x40], i32::MAX),
+            None
+        );
+    }
+
+    #[test]
+    fn data_near_2gib_is_passed_through() {
+        let data = "wasm-squeeze ".repeat(0x100);
+        for offset in [0x7fff_f2ff, 0x7fff_f800] {
+            let input = wat::parse_str(format!(
+                r#"(module
+                    (memory 0x8000)
+                    (func $start)
+                    (start $start)
+                    (data (i32.const {offset}) "{data}"))"#
+            ))
+            .unwrap();
+            let outcome = squeeze_slice(&input, &SqueezeOptions::default()).unwrap();
+            assert_eq!(
+                outcome.passthrough_reason,
+                Some(PassthroughReason::InsufficientMemory),
+                "{offset:#x}"
+            );
+            assert_eq!(outcome.output, input, "{offset:#x}");
+        }
+    }
+
+    #[test]
+    fn incompressible_data_adds_no_code() {
+        // Pseudo-random bytes split into adjacent segments, which only merging shrinks
+        let mut state = 0x2545_f491u32;
+        let segments: String = (0..4)
+            .map(|i| {
+                let bytes: String = (0..0x100)
+                    .map(|_| {
+                        state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                        format!("\\{:02x}", state >> 24)
+                    })
+                    .collect();
+                format!(r#"(data (i32.const {}) "{bytes}")"#, 0x2000 + i * 0x100)
+            })
+            .collect();
+        let input = wat::parse_str(format!(
+            r#"(module
+                (memory 1)
+                (func $start)
+                (start $start)
+                {segments})"#
+        ))
+        .unwrap();
+
+        // Type and function counts, the start function and the code section size
+        fn code_shape(module: &[u8]) -> (u32, u32, Option<u32>, u32) {
+            let mut shape = (0, 0, None, 0);
+            for payload in wp::Parser::new(0).parse_all(module) {
+                match payload.unwrap() {
+                    wp::Payload::TypeSection(types) => shape.0 = types.count(),
+                    wp::Payload::FunctionSection(functions) => shape.1 = functions.count(),
+                    wp::Payload::StartSection { func, .. } => shape.2 = Some(func),
+                    wp::Payload::CodeSectionStart { size, .. } => shape.3 = size,
+                    _ => (),
+                }
+            }
+            shape
+        }
+
+        let outcome = squeeze_slice(&input, &SqueezeOptions::default()).unwrap();
+        assert_eq!(outcome.passthrough_reason, None);
+        assert_eq!(
+            outcome.compression_skipped,
+            Some(PassthroughReason::IncompressibleData)
+        );
+        assert!(outcome.output.len() < input.len());
+        validate_output(&outcome.output).unwrap();
+        assert_eq!(code_shape(&outcome.output), code_shape(&input));
+    }
+
+    #[test]
+    fn compressed_globals_are_restored() {
+        let data = "wasm-squeeze ".repeat(0x100);
+        let input = wat::parse_str(format!(
+            r#"(module
+                (memory 1)
+                (global (mut i32) (i32.const 0x1234))
+                (global (mut f64) (f64.const 1.5))
+                (func $start)
+                (start $start)
+                (data (i32.const 0x2000) "{data}"))"#
+        ))
+        .unwrap();
+        let options = SqueezeOptions {
+            compress_globals: true,
+            ..SqueezeOptions::default()
+        };
+        let outcome = squeeze_slice(&input, &options).unwrap();
+        assert_eq!(outcome.passthrough_reason, None);
+        validate_output(&outcome.output).unwrap();
+        let wat = outcome.prologue_wat.unwrap();
+        assert!(wat.contains("i32.load align=1\nglobal.set 0\n"), "{wat}");
+        assert!(wat.contains("f64.load align=1\nglobal.set 1\n"), "{wat}");
+        assert!(!wat.contains(";; "), "{wat}");
+    }
+
+    #[test]
+    fn microw8_palette_and_font_are_kept() {
+        let data = "wasm-squeeze ".repeat(0x100);
+        let input = wat::parse_str(format!(
+            r#"(module
+                (import "env" "memory" (memory 4))
+                (func $start)
+                (start $start)
+                (data (i32.const 0x14000) "{data}"))"#
+        ))
+        .unwrap();
+        let options = SqueezeOptions {
+            target: Target::MicroW8,
+            ..SqueezeOptions::default()
+        };
+        let outcome = squeeze_slice(&input, &options).unwrap();
+        assert_eq!(outcome.passthrough_reason, None);
+        validate_output(&outcome.output).unwrap();
+        assert!(!outcome.memory_writes.is_empty());
+        for write in &outcome.memory_writes {
+            let names: Vec<_> = Target::MicroW8
+                .profile()
+                .region_names(write.range.clone())
+                .collect();
+            assert!(names.is_empty(), "{write:?} overwrites {names:?}");
+        }
+    }
+
+    #[test]
+    fn relative_data_leaves_memory_past_the_reserved_size() {
+        const BASE: usize = 0x1000;
+        let data = "wasm-squeeze ".repeat(0x100);
+        let module = wat::parse_str(format!(
+            r#"(module
+                (import "env" "memory" (memory 1))
+                (import "env" "__memory_base" (global i32))
+                (func $start)
+                (start $start)
+                (data (global.get 0) "{data}"))"#
+        ))
+        .unwrap();
+        // WASM-4 registers are at absolute addresses below the base, which the module does not own
+        let options = SqueezeOptions {
+            target: Target::Generic,
+            ..SqueezeOptions::default()
+        };
+        assert_eq!(
+            squeeze_slice(&module, &options).unwrap().passthrough_reason,
+            Some(PassthroughReason::InsufficientMemory)
+        );
+
+        // `dylink.0` section reserving exactly the data's 0xd00 bytes comes first
+        let dylink = [&[0, 16, 8][..], b"dylink.0", &[1, 5, 0x80, 0x1a, 0, 0, 0]].concat();
+        let input = [&module[..8], &dylink, &module[8..]].concat();
+        let outcome = squeeze_slice(&input, &options).unwrap();
+        assert_eq!(outcome.passthrough_reason, None);
+        validate_output(&outcome.output).unwrap();
+        let reserved = wp::Parser::new(0)
+            .parse_all(&outcome.output)
+            .find_map(|payload| match payload.unwrap() {
+                wp::Payload::CustomSection(section) if section.name() == "dylink.0" => {
+                    dylink_memory_size(&section).unwrap()
+                }
+                _ => None,
+            })
+            .unwrap() as usize;
+        assert!(data.len() < reserved);
+
+        let engine = wasmi::Engine::default();
+        let module = wasmi::Module::new(&engine, &outcome.output[..]).unwrap();
+        let mut store = wasmi::Store::new(&engine, ());
+        let memory_type = wasmi::MemoryType::new(1, None).unwrap();
+        let memory = wasmi::Memory::new(&mut store, memory_type).unwrap();
+        memory.data_mut(&mut store).fill(0xa5);
+        let base = wasmi::Global::new(
+            &mut store,
+            wasmi::Val::I32(BASE as i32),
+            wasmi::Mutability::Const,
+        );
+        let mut linker = wasmi::Linker::new(&engine);
+        linker.define("env", "memory", memory).unwrap();
+        linker.define("env", "__memory_base", base).unwrap();
+        linker
+            .instantiate(&mut store, &module)
+            .unwrap()
+            .start(&mut store)
+            .unwrap();
+
+        let memory = memory.data(&store);
+        let (before, rest) = memory.split_at(BASE);
+        let (reserved, after) = rest.split_at(reserved);
+        assert!(before.iter().all(|&byte| byte == 0xa5));
+        assert!(after.iter().all(|&byte| byte == 0xa5));
+        assert_eq!(&reserved[..data.len()], data.as_bytes());
+        assert!(reserved[data.len()..].iter().all(|&byte| byte == 0));
+    }
+}
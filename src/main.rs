@@ -1,755 +1,1427 @@
 use std::{
-    error::Error,
-    fmt,
+    env, fmt,
     fs::File,
-    io::{self, IsTerminal, Write},
+    io::{self, IsTerminal, Read, Write},
     iter,
+    num::NonZeroUsize,
     ops::Range,
     path::{Path, PathBuf},
     process,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
 };
 
 use anyhow::Context;
-use clap::Parser;
-use wasm_encoder::{
-    self as we,
-    reencode::{self, Reencode},
+use clap::{
+    builder::{PossibleValue, PossibleValuesParser, TypedValueParser},
+    CommandFactory, FromArgMatches, Parser, Subcommand,
 };
-use wasmparser::{self as wp, FromReader};
-
-/// Supported wasm features
-const WASM_FEATURES: wp::WasmFeatures = {
-    use wp::WasmFeatures as Ft;
-
-    Ft::BULK_MEMORY
-        .union(Ft::EXCEPTIONS)
-        .union(Ft::FLOATS)
-        .union(Ft::FUNCTION_REFERENCES)
-        .union(Ft::GC)
-        .union(Ft::LEGACY_EXCEPTIONS)
-        .union(Ft::MULTI_VALUE)
-        .union(Ft::MUTABLE_GLOBAL)
-        .union(Ft::REFERENCE_TYPES)
-        .union(Ft::RELAXED_SIMD)
-        .union(Ft::SATURATING_FLOAT_TO_INT)
-        .union(Ft::SIGN_EXTENSION)
-        .union(Ft::SIMD)
-        .union(Ft::TAIL_CALL)
+use wasm_squeeze::{
+    stream::{ForeignFormat, NotWasmError},
+    CustomUnpacker, Packer, PackerConfig, PassthroughReason, RegisterValue, Renames,
+    RequirementStatus, SqueezeError, SqueezeOptions, SqueezeOutcome, Target,
 };
-const UNPACKER_WASM: &[u8] = include_bytes!("upkr_unpacker.wasm");
-
-const MEM_SIZE: i32 = 0x10000;
-const CONTEXT_OFFSET: i32 = 0;
-const COMPRESSED_DATA_OFFSET: i32 = common::CONTEXT_SIZE;
-const PALETTE_OFFSET: i32 = 4;
-const PALETTE_DEFAULT: [i64; 2] = [0x0086c06c_00e0f8cf, 0x00071821_00306850];
-const DRAW_COLORS_DEFAULT: i16 = 0x1203;
-const DRAW_COLORS_OFFSET: i32 = 0x14;
-const MOUSE_XY_DEFAULT: i32 = 0x7fff7fff;
-const MOUSE_XY_OFFSET: i32 = 0x1a;
+
+const EXAMPLES: &str = "\
+Examples:
+  wasm-squeeze cart.wasm -o cart-squeezed.wasm
+  wasm-squeeze --target microw8 --in-place cart.wasm -o cart-squeezed.wasm
+  wasm-squeeze --dry-run --metrics json cart.wasm
+  wasm-squeeze --output-dir squeezed/ carts/*.wasm
+  wasm-squeeze --suffix .sq.wasm carts/*.wasm
+  wasm-squeeze --from html --bundle game.html -o game-squeezed.html
+  wasm-opt -Oz cart.wasm -o - | wasm-squeeze - | wasm-opt -Oz - -o cart-squeezed.wasm";
 
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true, after_long_help = EXAMPLES)]
 struct Args {
-    /// Input wasm file path. Specify `-` to use stdin.
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Input wasm file paths. Specify `-` to use stdin. Several inputs require `--output-dir`,
+    /// `--suffix` or `--overwrite`.
     #[clap(default_value = "-")]
-    input: PathBuf,
+    input: Vec<PathBuf>,
     /// Output wasm file path. Specify `-` to use stdout.
     #[clap(short, long, default_value = "-")]
     output: PathBuf,
+    /// Write outputs into this directory under the names of the inputs, summarizing all of them
+    /// at the end.
+    #[clap(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = [
+            "output",
+            "metrics_file",
+            "save_crash_bundle",
+            "emit_prologue_wat",
+            "emit_wat",
+            "emit_rs_embed",
+            "emit_c_embed",
+        ],
+    )]
+    output_dir: Option<PathBuf>,
+    /// Write outputs next to the inputs, or into `--output-dir`, replacing their `.wasm`
+    /// extension with this suffix, like `.sq.wasm`.
+    #[clap(long, value_name = "SUFFIX", conflicts_with = "output")]
+    suffix: Option<String>,
+    /// Replace the input files with the squeezed outputs.
+    #[clap(long, conflicts_with_all = ["output", "output_dir", "suffix"])]
+    overwrite: bool,
+    /// Emit a machine-readable summary of squeezing to stderr, or to `--metrics-file`.
+    #[clap(long, value_name = "FORMAT", value_parser = PossibleValuesParser::new(["json"]))]
+    metrics: Option<String>,
+    /// Write metrics into the specified file instead of stderr.
+    #[clap(long, requires = "metrics")]
+    metrics_file: Option<PathBuf>,
+    /// Version of machine-readable outputs to emit. Fields are only added within a version,
+    /// removing or changing any of them bumps it.
+    #[clap(
+        long,
+        value_name = "VERSION",
+        default_value_t = API_VERSION,
+        value_parser = clap::value_parser!(u32).range(1..=i64::from(API_VERSION)),
+    )]
+    api_version: u32,
+    /// Fail without writing the output if squeezing would pass through the input, because
+    /// there is no data or compression does not pay off. Exits with code 3, unlike a pass
+    /// through writing the input, which exits with code 2.
+    #[clap(long, visible_alias = "fail-if-larger")]
+    strict: bool,
+    /// Format of the input: a wasm module, an HTML page made by `w4 bundle --html` (also
+    /// accepted as `bundle`) or WebAssembly text.
+    #[clap(
+        long,
+        value_name = "FORMAT",
+        default_value = "wasm",
+        value_parser = PossibleValuesParser::new([
+            PossibleValue::new("wasm"),
+            PossibleValue::new("html").alias("bundle"),
+            PossibleValue::new("wat"),
+        ])
+        .map(|from| match from.as_str() {
+            "bundle" => "html".to_owned(),
+            _ => from,
+        }),
+    )]
+    from: String,
+    /// Write the output as the input HTML page with the squeezed cart, requires `--from html`.
+    #[clap(long)]
+    bundle: bool,
+    /// Fail after writing the output if it is larger than this many bytes, like the 64KiB
+    /// cartridge size limit of WASM-4.
+    #[clap(long, value_name = "BYTES")]
+    size_limit: Option<usize>,
+    /// Remove functions, globals and other items unreachable from exports and the start function
+    /// from the input before squeezing it.
+    #[clap(long)]
+    gc: bool,
+    /// Run `wasm-opt` with these arguments, like `-Oz`, on the input before squeezing it. The
+    /// `WASM_OPT` environment variable overrides the program.
+    #[clap(long, value_name = "ARGS")]
+    pre_opt: Option<String>,
+    /// Run `wasm-opt` with these arguments on the squeezed module, keeping the result if it is
+    /// smaller and valid. Size metrics describe the optimized module, while the prologue wat and
+    /// `--explain-memory` describe the module before optimizing.
+    #[clap(long, value_name = "ARGS")]
+    post_opt: Option<String>,
+    /// Validate the squeezed module before writing it, failing if the injected code or the
+    /// remapped indices made it invalid.
+    #[clap(long)]
+    validate_output: bool,
+    /// Squeeze and report the result without writing the output or the prologue wat.
+    #[clap(long)]
+    dry_run: bool,
+    /// On internal errors, write the input, the command line, the tool version and the error
+    /// into this directory to attach to a bug report.
+    #[clap(long, value_name = "DIR")]
+    save_crash_bundle: Option<PathBuf>,
+    /// Write only section and data segment sizes of the input into the crash bundle instead of
+    /// the input itself.
+    #[clap(long, requires = "save_crash_bundle")]
+    no_input_in_bundle: bool,
     /// The compression level (0-9)
     #[clap(short, long, default_value = "9")]
     level: u8,
+    /// Try every compression level and keep the smallest output. Other upkr settings must match
+    /// the embedded unpacker, so only levels are searched unless `--best-formats` is given.
+    #[clap(long, conflicts_with = "level")]
+    best: bool,
+    /// Also search parity contexts and the bitstream mode of the compressed format, logging the
+    /// one which wins. The imported unpack function has to decode that format.
+    #[clap(
+        long,
+        requires_all = ["best", "unpacker_as_import"],
+        conflicts_with_all = ["parity_contexts", "bitstream"],
+    )]
+    best_formats: bool,
+    /// Number of threads to search compression levels with, all available cores by default.
+    #[clap(short, long, requires = "best")]
+    jobs: Option<NonZeroUsize>,
+    /// Stop trying further compression levels after this many seconds, keeping the best output
+    /// found so far. Levels already being tried are finished.
+    #[clap(long, value_name = "SECONDS", requires = "best")]
+    effort: Option<f64>,
+    /// Place the unpacker context inside of the original data region, which is
+    /// overwritten with decompressed data last, instead of the low memory.
+    #[clap(long)]
+    context_in_data: bool,
+    /// Do not compress data, only merge data segments.
+    #[clap(long)]
+    no_compress: bool,
+    /// Do not inject the unpacker, simply reencode the input module.
+    #[clap(long, requires = "no_compress")]
+    no_inject: bool,
+    /// Report zero gaps between data segments of at least this many bytes.
+    #[clap(long, default_value = "1024")]
+    report_gaps: usize,
+    /// Write the injected prologue instructions as a wat snippet into the specified file.
+    #[clap(long)]
+    emit_prologue_wat: Option<PathBuf>,
+    /// Also write the whole output module in the text format into the specified file.
+    #[clap(long, value_name = "PATH")]
+    emit_wat: Option<PathBuf>,
+    /// Also write the output as a Rust source file defining `pub const CART: &[u8]`, for
+    /// embedding the cart into another Rust program.
+    #[clap(long, value_name = "PATH")]
+    emit_rs_embed: Option<PathBuf>,
+    /// Also write the output as a C header defining the `cart` array and `CART_SIZE`, for
+    /// compiling the cart into native runtimes and firmware images.
+    #[clap(long, value_name = "PATH")]
+    emit_c_embed: Option<PathBuf>,
+    /// Rename imports and exports according to the mapping file. Each line of it is either
+    /// `import <module> <name> <new module> <new name>` or `export <name> <new name>`.
+    /// Only useful for custom embedders, WASM-4 expects its original import names.
+    #[clap(long)]
+    rename: Option<PathBuf>,
+    /// Runtime which state is restored after decompression: wasm4, microw8 or generic.
+    #[clap(long, default_value = "wasm4")]
+    target: Target,
+    /// Pass through the input module on internal errors instead of failing.
+    #[clap(long)]
+    keep_going: bool,
+    /// Compress clusters of data separated by zero gaps of at least this many bytes separately,
+    /// emitting an unpack call per cluster, if that comes out smaller.
+    #[clap(long, value_name = "BYTES", conflicts_with_all = ["context_in_data", "in_place"])]
+    split_gaps: Option<usize>,
+    /// Do not merge data segments separated by gaps larger than this many bytes, compressing
+    /// them separately with an unpack call each.
+    #[clap(long, value_name = "BYTES", conflicts_with_all = ["context_in_data", "in_place"])]
+    max_gap: Option<usize>,
+    /// Wasm feature the target runtime lacks. Squeezing fails if injected code requires it.
+    #[clap(
+        long,
+        value_name = "FEATURE",
+        value_parser = PossibleValuesParser::new(
+            wasm_squeeze::OPTIONAL_FEATURES.iter().map(|&(name, _)| name)
+        ),
+    )]
+    disable_feature: Vec<String>,
+    /// Run the unpacker on the compressed data under an interpreter and report executed
+    /// instructions and host time. Requires the `bench` feature.
+    #[clap(long)]
+    bench_unpack: bool,
+    /// Run start functions of the input and the squeezed modules under an interpreter with stub
+    /// imports and fail unless they leave the same memory. Requires the `verify-exec` feature.
+    #[clap(long)]
+    verify_exec: bool,
+    /// Report every range of memory written before the original start function runs, for
+    /// auditing memory-mapped I/O regions.
+    #[clap(long)]
+    explain_memory: bool,
+    /// Move non-zero initializers of mutable globals into the compressed data, restoring them
+    /// in the prologue.
+    #[clap(long, conflicts_with_all = ["in_place", "split_gaps"])]
+    compress_globals: bool,
+    /// Import the unpack function `(context, destination, compressed) -> length` instead of
+    /// injecting the unpacker, for custom embedders providing it natively.
+    #[clap(long, num_args = 2, value_names = ["MODULE", "FIELD"])]
+    unpacker_as_import: Option<Vec<String>>,
+    /// Select literal and match contexts by the low bits of the position, using this many of
+    /// them (1, 2 or 4). Needs a matching `--unpacker-as-import`.
+    #[clap(
+        long,
+        default_value = "1",
+        value_parser = PossibleValuesParser::new(["1", "2", "4"])
+            .map(|contexts| contexts.parse::<usize>().unwrap()),
+    )]
+    parity_contexts: usize,
+    /// Invert the encoding of bits. Needs a matching `--unpacker-as-import`.
+    #[clap(long)]
+    invert_bit_encoding: bool,
+    /// Update probabilities in a simplified way. Needs a matching `--unpacker-as-import`.
+    #[clap(long)]
+    simplified_prob_update: bool,
+    /// Encode into a bitstream instead of a bytestream. Needs a matching
+    /// `--unpacker-as-import`.
+    #[clap(long)]
+    bitstream: bool,
+    /// Inject this unpacker module instead of the embedded one. It may only import
+    /// `env.memory` and must export a single function `(context, destination, compressed) ->
+    /// end`. Needs `--pack-cmd` producing data for it.
+    #[clap(
+        long,
+        value_name = "PATH",
+        requires = "pack_cmd",
+        conflicts_with_all = ["unpacker_as_import", "in_place"],
+    )]
+    unpacker: Option<PathBuf>,
+    /// Compress data for the `--unpacker` with this command, which reads data from stdin and
+    /// writes it compressed to stdout. The compression level is passed in the
+    /// `WASM_SQUEEZE_LEVEL` environment variable.
+    #[clap(long, value_name = "COMMAND", requires = "unpacker")]
+    pack_cmd: Option<String>,
+    /// Decompress data right into its place, which saves the copy from the top of the memory.
+    #[clap(long, conflicts_with = "context_in_data")]
+    in_place: bool,
+    /// Drop all custom sections, like names and producers, from the squeezed output. A passed
+    /// through input keeps them.
+    #[clap(long)]
+    strip: bool,
+    /// Drop custom sections with this name from the squeezed output. May be repeated.
+    #[clap(long, value_name = "NAME", conflicts_with = "strip")]
+    strip_custom: Vec<String>,
+    /// Unsqueeze an input which is squeezed already and squeeze it again with these options,
+    /// instead of passing it through.
+    #[clap(long)]
+    resqueeze: bool,
+    /// Warn instead of failing on a module of a binary version newer than known, squeezing it
+    /// as version 1 and keeping its version in the output.
+    #[clap(long)]
+    best_effort: bool,
+    /// Decompress the packed data in-process and fail unless it matches the original data,
+    /// guarding against a packer not matching the unpacker.
+    #[clap(long, conflicts_with = "unpacker")]
+    assert_roundtrip: bool,
+    /// Never use or zero this memory range, like `0x19a0..0x1a00`, failing if decompression
+    /// does not fit around it. May be repeated.
+    #[clap(long, value_name = "START..END", value_parser = parse_range)]
+    reserve: Vec<Range<i32>>,
+    /// If compression does not pay off, still emit merged and trimmed data segments without
+    /// the unpacker instead of passing through the input.
+    #[clap(long, conflicts_with = "no_inject")]
+    merge_fallback: bool,
 }
 
+#[derive(Subcommand)]
+enum Command {
+    /// Only analyze the input and print which requirements for squeezing it meets
+    #[command(after_long_help = "Example:\n  wasm-squeeze check cart.wasm")]
+    Check {
+        /// Input wasm file path. Specify `-` to use stdin.
+        #[clap(default_value = "-")]
+        input: PathBuf,
+    },
+    /// Print section sizes, data statistics and predicted savings without writing any output
+    #[command(after_long_help = "Example:\n  wasm-squeeze inspect cart.wasm")]
+    Inspect {
+        /// Input wasm file path. Specify `-` to use stdin.
+        #[clap(default_value = "-")]
+        input: PathBuf,
+        /// Runtime which memory map data segments are annotated with: wasm4, microw8 or
+        /// generic.
+        #[clap(long, default_value = "wasm4")]
+        target: Target,
+    },
+    /// Decompress data of a squeezed module and strip the injected code
+    #[command(
+        after_long_help = "Example:\n  wasm-squeeze unsqueeze cart-squeezed.wasm -o cart.wasm"
+    )]
+    Unsqueeze {
+        /// Input wasm file path. Specify `-` to use stdin.
+        #[clap(default_value = "-")]
+        input: PathBuf,
+        /// Output wasm file path. Specify `-` to use stdout.
+        #[clap(short, long, default_value = "-")]
+        output: PathBuf,
+        /// Runtime the module was squeezed for: wasm4, microw8 or generic.
+        #[clap(long, default_value = "wasm4")]
+        target: Target,
+    },
+    /// Squeeze every wasm module of a directory with each configuration and print a table of
+    /// sizes, ratios and times to compare them
+    #[command(
+        after_long_help = "Example:\n  wasm-squeeze bench carts/ --config '' --config '--in-place'"
+    )]
+    Bench {
+        /// Directory with wasm modules.
+        corpus: PathBuf,
+        /// Squeezing options like `--level 4 --in-place`. May be repeated, defaults to the
+        /// default options.
+        #[clap(long, value_name = "ARGS")]
+        config: Vec<String>,
+    },
+    /// List runtimes the prologue may restore the state of, see `help targets` for details
+    Targets,
+    /// Print codecs, targets and optional components available in this build
+    #[command(after_long_help = "Example:\n  wasm-squeeze capabilities --json")]
+    Capabilities {
+        /// Print a JSON object instead of text.
+        #[clap(long)]
+        json: bool,
+    },
+    /// Print a shell completion script
+    #[command(after_long_help = "Example:\n  wasm-squeeze completions fish > wasm-squeeze.fish")]
+    Completions { shell: clap_complete::Shell },
+    /// Print a man page in the roff format
+    Man,
+}
+
+/// Squeezing passed through the input in the strict mode
+#[derive(Debug)]
+struct IneffectiveError(PassthroughReason);
+
+impl fmt::Display for IneffectiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}, squeezing is ineffective", self.0)
+    }
+}
+
+impl std::error::Error for IneffectiveError {}
+
+/// How to get a wasm module out of an input in the `format`
+fn foreign_format_hint(format: ForeignFormat) -> &'static str {
+    match format {
+        ForeignFormat::Png => "Extract the embedded wasm module from the image first",
+        ForeignFormat::Gzip => "Decompress the input first, e.g. with `gunzip`",
+        ForeignFormat::Zip => "Unpack the wasm module from the archive first",
+        ForeignFormat::Html => "Use `--from html` if it is a `w4 bundle --html` output",
+        ForeignFormat::Wat => "Use `--from wat` or assemble it first, e.g. with `wat2wasm`",
+    }
+}
+
+/// Latest version of machine-readable outputs
+const API_VERSION: u32 = 1;
+
+/// Exit code signaling that the input was passed through instead of being squeezed
+const PASSTHROUGH_EXIT_CODE: u8 = 2;
+
 fn main() -> process::ExitCode {
     match try_main() {
-        Ok(()) => process::ExitCode::SUCCESS,
+        Ok(exit_code) => exit_code,
         Err(e) => {
             log::error!("{e:?}");
-            process::ExitCode::FAILURE
+            if let Some(NotWasmError(format)) = e
+                .chain()
+                .find_map(|cause| cause.downcast_ref::<NotWasmError>())
+            {
+                log::info!("{}", foreign_format_hint(*format));
+            }
+            if e.is::<IneffectiveError>() {
+                process::ExitCode::from(3)
+            } else {
+                process::ExitCode::FAILURE
+            }
         }
     }
 }
 
-fn try_main() -> anyhow::Result<()> {
+fn try_main() -> anyhow::Result<process::ExitCode> {
     env_logger::try_init_from_env(
         env_logger::Env::new()
             .filter_or("WASM_SQUEEZE_LOG", "info")
             .write_style("WASM_SQUEEZE_LOG_STYLE"),
     )?;
-    let args = Args::parse();
-    let input = if args.input == Path::new("-") {
-        Box::new(io::stdin().lock()) as Box<dyn io::Read>
-    } else {
-        Box::new(io::BufReader::new(File::open(&args.input)?))
-    };
+    let args = Args::from_arg_matches(&command().get_matches()).unwrap_or_else(|err| err.exit());
+    match &args.command {
+        Some(Command::Check { input }) => {
+            check(open_input(input)?)?;
+            return Ok(process::ExitCode::SUCCESS);
+        }
+        Some(Command::Completions { shell }) => {
+            clap_complete::generate(*shell, &mut command(), "wasm-squeeze", &mut io::stdout());
+            return Ok(process::ExitCode::SUCCESS);
+        }
+        Some(Command::Man) => {
+            clap_mangen::Man::new(command()).render(&mut io::stdout())?;
+            return Ok(process::ExitCode::SUCCESS);
+        }
+        Some(Command::Targets) => {
+            print!("{}", targets_help());
+            return Ok(process::ExitCode::SUCCESS);
+        }
+        Some(Command::Capabilities { json }) => {
+            capabilities(*json);
+            return Ok(process::ExitCode::SUCCESS);
+        }
+        Some(Command::Bench { corpus, config }) => {
+            bench(corpus, config)?;
+            return Ok(process::ExitCode::SUCCESS);
+        }
+        Some(Command::Inspect { input, target }) => {
+            inspect(open_input(input)?, *target)?;
+            return Ok(process::ExitCode::SUCCESS);
+        }
+        Some(Command::Unsqueeze {
+            input,
+            output,
+            target,
+        }) => {
+            let mut input_bytes = Vec::new();
+            open_input(input)?
+                .read_to_end(&mut input_bytes)
+                .context("reading input")?;
+            let module = wasm_squeeze::unsqueeze(&input_bytes, *target)?;
+            write_output(output, &module).context("writing an output wasm module")?;
+            return Ok(process::ExitCode::SUCCESS);
+        }
+        None => (),
+    }
+    if args.output_dir.is_some() || args.suffix.is_some() || args.overwrite {
+        return squeeze_files(&args);
+    }
+    anyhow::ensure!(
+        args.input.len() == 1,
+        "several inputs require `--output-dir`, `--suffix` or `--overwrite`"
+    );
+    let report = squeeze_file(&args, &args.input[0], &args.output)?;
+    Ok(match report.passed_through {
+        true => process::ExitCode::from(PASSTHROUGH_EXIT_CODE),
+        false => process::ExitCode::SUCCESS,
+    })
+}
 
-    let mut info = RelevantInfoBuilder::new();
-    let input = parse_stream_and_save(input, |payload| info.add_payload(payload))
-        .context("parsing input as wasm module")?;
-    // Input, but with mitigations like edited data count
-    let (info, mitigated_input) = match info.build(&input) {
-        Ok(x) => x,
-        Err(err) => {
-            for cause in err.chain() {
-                if cause.is::<NoDataError>() {
-                    log::warn!("No data to compress, simply passing through the input");
-                    write_output(&args, &input).context("writing an output wasm module")?;
-                    return Ok(());
-                }
+/// Sizes of a squeezed file
+struct FileReport {
+    input_size: usize,
+    output_size: usize,
+    passed_through: bool,
+}
+
+/// Squeeze every input into its own output, logging a summary of all of them
+fn squeeze_files(args: &Args) -> anyhow::Result<process::ExitCode> {
+    if let Some(output_dir) = &args.output_dir {
+        std::fs::create_dir_all(output_dir).context("creating the output directory")?;
+    }
+    let (mut input_size, mut output_size) = (0, 0);
+    let (mut passed_through, mut failures) = (0, 0);
+    for input in &args.input {
+        anyhow::ensure!(
+            input != Path::new("-"),
+            "stdin is only supported with `--output`"
+        );
+        log::info!("Squeezing {}", input.display());
+        match squeeze_file(args, input, &output_path(args, input)?) {
+            Ok(report) => {
+                input_size += report.input_size;
+                output_size += report.output_size;
+                passed_through += usize::from(report.passed_through);
+            }
+            Err(err) => {
+                log::error!("{}: {err:?}", input.display());
+                failures += 1;
             }
-            return Err(err);
         }
+    }
+    log::info!(
+        "Squeezed {} file(s) from {input_size} to {output_size} bytes ({:.2}%), {passed_through} \
+         passed through, {failures} failed",
+        args.input.len(),
+        100.0 * output_size as f64 / input_size.max(1) as f64
+    );
+    anyhow::ensure!(failures == 0, "{failures} input(s) failed");
+    Ok(match passed_through {
+        0 => process::ExitCode::SUCCESS,
+        _ => process::ExitCode::from(PASSTHROUGH_EXIT_CODE),
+    })
+}
+
+/// Output path for the `input` following `--output-dir`, `--suffix` and `--overwrite`
+fn output_path(args: &Args, input: &Path) -> anyhow::Result<PathBuf> {
+    if args.overwrite {
+        return Ok(input.to_owned());
+    }
+    let name = input.file_name().context("input path has no file name")?;
+    let name = match &args.suffix {
+        Some(suffix) => {
+            let stem = match input.extension() {
+                Some(extension) if extension == "wasm" => input.file_stem().unwrap_or(name),
+                _ => name,
+            };
+            format!("{}{suffix}", stem.to_string_lossy()).into()
+        }
+        None => name.to_owned(),
+    };
+    let dir = match &args.output_dir {
+        Some(dir) => dir.as_path(),
+        None => input.parent().unwrap_or(Path::new("")),
     };
-    log::debug!("Retrieved relevant info from the input module:\n{info:#?}");
-    let unpacker = UnpackerComponents::parse();
+    let output = dir.join(name);
+    anyhow::ensure!(
+        output != input,
+        "output path {} is the input, pass `--overwrite` to replace it",
+        output.display()
+    );
+    Ok(output)
+}
 
-    let module = reencode_with_unpacker(&mitigated_input, info, unpacker, args.level)?;
-    let output = module.finish();
+/// Squeeze the wasm module at the `input_path` into the `output_path`
+fn squeeze_file(args: &Args, input_path: &Path, output_path: &Path) -> anyhow::Result<FileReport> {
+    let mut input = open_input(input_path)?;
+    let options = args.squeeze_options()?;
+    anyhow::ensure!(
+        !args.bundle || args.from == "html",
+        "`--bundle` requires `--from html`"
+    );
 
-    let reduced_bytes = input.len() as isize - output.len() as isize;
-    if reduced_bytes <= 0 {
-        log::warn!(
-            "Compression did not reduce wasm module's size, simply passing through the input"
-        );
-        write_output(&args, &input).context("writing an output wasm module")?;
-    } else {
+    let html = match args.from.as_str() {
+        "html" => {
+            let mut html = String::new();
+            input
+                .read_to_string(&mut html)
+                .context("reading input HTML")?;
+            Some(html)
+        }
+        _ => None,
+    };
+    let mapped = match args.from.as_str() {
+        "wasm" if input_path != Path::new("-") => map_file(input_path)?,
+        _ => None,
+    };
+    let cart = match &html {
+        Some(html) => Some(
+            wasm_squeeze::bundle::extract_html_cart(html)
+                .context("extracting the cart from the input HTML")?,
+        ),
+        None if args.from == "wat" => {
+            let mut text = String::new();
+            input
+                .read_to_string(&mut text)
+                .context("reading input text")?;
+            Some(wat::parse_str(&text).context("assembling the input text")?)
+        }
+        // The whole input is needed to try it multiple times or to save it into a crash bundle
+        None if mapped.is_none()
+            && (args.best
+                || args.save_crash_bundle.is_some()
+                || args.gc
+                || args.pre_opt.is_some()) =>
+        {
+            let mut input_bytes = Vec::new();
+            input
+                .read_to_end(&mut input_bytes)
+                .context("reading input")?;
+            Some(input_bytes)
+        }
+        None => None,
+    };
+    let cart = cart.as_deref().or(mapped.as_deref());
+    let collected;
+    let cart = match cart {
+        Some(cart) if args.gc => {
+            collected = gc(cart).context("removing unreachable items from the input")?;
+            log::info!("Removing unreachable items gives {} bytes", collected.len());
+            Some(&collected[..])
+        }
+        cart => cart,
+    };
+    let optimized;
+    let cart = match (&args.pre_opt, cart) {
+        (Some(opt_args), Some(cart)) => {
+            optimized = wasm_opt(cart, opt_args).context("optimizing the input")?;
+            log::info!("Optimizing the input gives {} bytes", optimized.len());
+            Some(&optimized[..])
+        }
+        (_, cart) => cart,
+    };
+    // Writing over the input would truncate it while it is still mapped
+    let replaces_input = output_path == input_path && input_path != Path::new("-");
+    let write_output: fn(&Path, &[u8]) -> anyhow::Result<()> =
+        match args.overwrite || replaces_input {
+            true => replace_input,
+            false => write_output,
+        };
+    let result = match cart {
+        Some(cart) if args.best => squeeze_best(cart, &args),
+        Some(cart) => wasm_squeeze::squeeze_slice(cart, &options).map(|o| (o, args.level)),
+        None => wasm_squeeze::squeeze_reader(input, &options).map(|o| (o, args.level)),
+    };
+    if let (Some(dir), Some(cart)) = (&args.save_crash_bundle, cart) {
+        let internal_error = match &result {
+            Ok((outcome, _)) => {
+                outcome.passthrough_reason == Some(PassthroughReason::InternalError)
+            }
+            Err(err) => err.chain().any(|cause| cause.is::<SqueezeError>()),
+        };
+        if internal_error {
+            save_crash_bundle(dir, cart, args.no_input_in_bundle, &result)
+                .context("saving the crash bundle")?;
+            log::error!("Saved a crash bundle into {}", dir.display());
+        }
+    }
+    let (mut outcome, level) = result?;
+    if let (Some(opt_args), None) = (&args.post_opt, outcome.passthrough_reason) {
+        // Prologue uses `memory.copy` and `memory.fill`
+        let opt_args = format!("--enable-bulk-memory {opt_args}");
+        let optimized = wasm_opt(&outcome.output, &opt_args).context("optimizing the output")?;
+        log::info!("Optimizing the output gives {} bytes", optimized.len());
+        if optimized.len() < outcome.output.len() {
+            wasm_squeeze::validate_output(&optimized).context("validating the optimized output")?;
+            // Size metrics describe the final module
+            outcome.candidate_size = Some(optimized.len());
+            outcome.output = optimized;
+        }
+    }
+    match outcome.passthrough_reason {
+        Some(reason) => {
+            log::warn!("{reason}, simply passing through the input");
+            log::debug!(
+                "Candidate module size: {:?}, packed data size: {:?}",
+                outcome.candidate_size,
+                outcome.packed_data_size
+            );
+        }
+        None => {
+            let reduced_bytes = outcome.input_size as isize - outcome.output.len() as isize;
+            log::info!(
+                "Reduced wasm module size by {} bytes ({:.2}%)",
+                reduced_bytes,
+                (100.0 * reduced_bytes as f64 / outcome.input_size as f64)
+            );
+        }
+    }
+    if let Some(bench) = &outcome.unpack_bench {
         log::info!(
-            "Reduced wasm module size by {} bytes ({:.2}%)",
-            reduced_bytes,
-            (100.0 * reduced_bytes as f64 / input.len() as f64)
+            "Unpacking took about {} instructions and {:?} on the host",
+            bench.fuel,
+            bench.host_time
         );
-        write_output(&args, &output).context("writing an output wasm module")?;
     }
-    Ok(())
-}
-
-fn write_output(args: &Args, output: &[u8]) -> Result<(), anyhow::Error> {
-    Ok(if args.output == Path::new("-") {
+    if args.explain_memory {
+        for write in &outcome.memory_writes {
+            let range = &write.range;
+            log::info!(
+                "{:#x}..{:#x} ({} bytes) is written on startup: {}{}",
+                range.start,
+                range.end,
+                range.len(),
+                write.kind,
+                region_note(args.target, range.start as u64, range.len())
+            );
+        }
+    }
+    if args.metrics.is_some() {
+        let metrics = metrics_json(&outcome, level, args.api_version);
+        match &args.metrics_file {
+            Some(path) => std::fs::write(path, metrics).context("writing metrics")?,
+            None => eprintln!("{metrics}"),
+        }
+    }
+    match outcome.passthrough_reason {
+        // Passing through is what was asked for in these cases
+        Some(PassthroughReason::CompressionDisabled | PassthroughReason::InternalError) => (),
+        Some(reason) if args.strict => return Err(IneffectiveError(reason).into()),
+        _ => (),
+    }
+    if args.validate_output && outcome.passthrough_reason.is_none() {
+        wasm_squeeze::validate_output(&outcome.output)?;
+    }
+    if !args.dry_run {
+        if let Some(path) = &args.emit_prologue_wat {
+            match &outcome.prologue_wat {
+                Some(wat) => std::fs::write(path, wat).context("writing prologue wat")?,
+                None => log::warn!("Data is not compressed, no prologue to emit"),
+            }
+        }
+        if let Some(path) = &args.emit_wat {
+            let wat = wasmprinter::print_bytes(&outcome.output).context("printing output wat")?;
+            std::fs::write(path, wat).context("writing output wat")?;
+        }
+        if let Some(path) = &args.emit_rs_embed {
+            std::fs::write(path, rs_embed(&outcome.output)).context("writing Rust source")?;
+        }
+        if let Some(path) = &args.emit_c_embed {
+            std::fs::write(path, c_embed(&outcome.output)).context("writing C header")?;
+        }
+        match &html {
+            Some(html) if args.bundle => {
+                let html = wasm_squeeze::bundle::replace_html_cart(html, &outcome.output)?;
+                write_output(output_path, html.as_bytes()).context("writing an output HTML")?;
+            }
+            _ => write_output(output_path, &outcome.output)
+                .context("writing an output wasm module")?,
+        }
+    }
+    if let Some(limit) = args.size_limit {
+        // Output is still written to see what takes the space
         anyhow::ensure!(
-            !io::stdout().is_terminal(),
-            "stdout is a terminal, cannot print the output wasm binary file"
+            outcome.output.len() <= limit,
+            "Output of {} bytes exceeds the size limit of {limit} bytes by {} bytes",
+            outcome.output.len(),
+            outcome.output.len() - limit
         );
-        io::stdout().lock().write_all(output)?;
-    } else {
-        std::fs::write(&args.output, output)?;
+    }
+    Ok(FileReport {
+        input_size: outcome.input_size,
+        output_size: outcome.output.len(),
+        passed_through: outcome.passthrough_reason.is_some(),
     })
 }
 
-fn parse_stream_and_save<'a, R, F>(mut reader: R, mut consumer: F) -> anyhow::Result<Vec<u8>>
-where
-    R: io::Read,
-    F: FnMut(wp::Payload) -> anyhow::Result<()>,
-{
-    let mut input_buffer = Vec::new();
-
-    let mut consumed_bytes = 0;
-    let mut eof = false;
-    let mut parser = wp::Parser::new(0);
-    parser.set_features(WASM_FEATURES);
-
-    loop {
-        let chunk = parser.parse(&input_buffer[consumed_bytes..], eof)?;
-
-        let payload = match chunk {
-            wp::Chunk::NeedMoreData(more_bytes) => {
-                let len = input_buffer.len();
-                input_buffer.resize(
-                    len.checked_add(more_bytes.try_into()?)
-                        .context("parser asks for too much bytes")?,
-                    0,
-                );
-                match reader.read(&mut input_buffer[len..]) {
-                    Ok(filled_bytes) => {
-                        if filled_bytes == 0 {
-                            eof = true;
-                        }
-                        input_buffer.resize_with(len + filled_bytes, || unreachable!())
-                    }
-                    Err(err) => match err.kind() {
-                        io::ErrorKind::Interrupted => {
-                            input_buffer.resize_with(len, || unreachable!())
-                        }
-                        _ => return Err(err.into()),
-                    },
-                }
-                continue;
-            }
-            wp::Chunk::Parsed { consumed, payload } => {
-                consumed_bytes = consumed_bytes + consumed;
-                payload
-            }
-        };
+/// Compressed formats searched by `--best`, which are other than the given one only with
+/// `--best-formats`
+fn best_packer_configs(args: &Args, packer: PackerConfig) -> Vec<PackerConfig> {
+    if !args.best_formats {
+        return vec![packer];
+    }
+    let parity_contexts = [1, 2, 4];
+    parity_contexts
+        .into_iter()
+        .flat_map(|parity_contexts| {
+            [false, true].map(|bitstream| PackerConfig {
+                parity_contexts,
+                bitstream,
+                ..packer
+            })
+        })
+        .collect()
+}
 
-        let is_end = matches!(payload, wp::Payload::End(_));
-        consumer(payload).context("payload `consumer` error")?;
-        if is_end {
-            break;
+/// Squeeze the `input` with every compression level and format on `args.jobs` threads within
+/// the `args.effort` time budget, returning the smallest outcome with its level
+fn squeeze_best(input: &[u8], args: &Args) -> anyhow::Result<(SqueezeOutcome, u8)> {
+    let jobs = match args.jobs {
+        Some(jobs) => jobs.get(),
+        None => thread::available_parallelism().map_or(1, NonZeroUsize::get),
+    };
+    let deadline = match args.effort {
+        Some(seconds) => Some(
+            Instant::now()
+                + Duration::try_from_secs_f64(seconds).context("invalid effort duration")?,
+        ),
+        None => None,
+    };
+    let options = args.squeeze_options()?;
+    let configs = best_packer_configs(args, options.packer);
+    // Ordered by level, as higher ones take longer
+    let candidates: Vec<_> = (0..10u8)
+        .flat_map(|level| configs.iter().map(move |&config| (level, config)))
+        .collect();
+    let candidates = &candidates;
+    let squeeze = |(level, packer): (u8, PackerConfig)| {
+        let options = SqueezeOptions {
+            level,
+            packer,
+            ..options.clone()
+        };
+        let outcome = wasm_squeeze::squeeze_slice(input, &options)?;
+        log::debug!(
+            "Level {level} with {packer:?} gives {} bytes",
+            outcome.output.len()
+        );
+        anyhow::Ok((outcome, level, packer))
+    };
+    let results: Vec<_> = thread::scope(|scope| {
+        // Candidates are interleaved between threads
+        let workers: Vec<_> = (0..jobs.min(candidates.len()))
+            .map(|first| {
+                scope.spawn(move || {
+                    (first..candidates.len())
+                        .step_by(jobs)
+                        // First candidate is always tried to have some result
+                        .filter(|&i| {
+                            i == 0 || deadline.map_or(true, |deadline| Instant::now() < deadline)
+                        })
+                        .map(|i| (candidates[i], squeeze(candidates[i])))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("squeezing thread panicked"))
+            .collect()
+    });
+
+    let tried = results.len();
+    let mut first_error = None;
+    let mut outcomes = Vec::new();
+    for ((level, packer), result) in results {
+        match result {
+            Ok(outcome) => outcomes.push(outcome),
+            Err(err) => {
+                log::warn!("Skipping level {level} with {packer:?}: {err:#}");
+                first_error.get_or_insert(err);
+            }
         }
     }
-
-    Ok(input_buffer)
+    if tried < candidates.len() {
+        log::info!(
+            "Effort budget ran out after trying {tried} of {} compression levels and formats",
+            candidates.len()
+        );
+    }
+    if outcomes.is_empty() {
+        return Err(first_error.expect("at least one level is tried"));
+    }
+    let (outcome, level, packer) = outcomes
+        .into_iter()
+        .min_by_key(|(outcome, level, _)| (outcome.output.len(), *level))
+        .expect("at least one level succeeded");
+    match args.best_formats {
+        true => log::info!(
+            "Compression level {level} with {} parity contexts and {} gives the smallest output",
+            packer.parity_contexts,
+            match packer.bitstream {
+                true => "a bitstream",
+                false => "a bytestream",
+            }
+        ),
+        false => log::info!("Compression level {level} gives the smallest output"),
+    }
+    Ok((outcome, level))
 }
 
-#[derive(Debug)]
-struct RelevantInfo {
-    start_fn_idx: Option<u32>,
-    data: Data<Vec<u8>>,
-    old_function_count: u32,
-    old_type_count: u32,
-    import_function_count: u32,
+impl Args {
+    fn squeeze_options(&self) -> anyhow::Result<SqueezeOptions> {
+        let renames = match &self.rename {
+            Some(path) => Renames::parse(
+                &std::fs::read_to_string(path).context("reading the rename mapping file")?,
+            )
+            .context("parsing the rename mapping file")?,
+            None => Renames::default(),
+        };
+        let custom_unpacker = match (&self.unpacker, &self.pack_cmd) {
+            (Some(path), Some(command)) => Some(CustomUnpacker {
+                wasm: std::fs::read(path).context("reading the unpacker module")?,
+                packer: Arc::new(CommandPacker {
+                    command: command.clone(),
+                }),
+            }),
+            _ => None,
+        };
+        Ok(SqueezeOptions {
+            level: self.level,
+            context_in_data: self.context_in_data,
+            no_compress: self.no_compress,
+            no_inject: self.no_inject,
+            report_gaps: self.report_gaps,
+            renames,
+            target: self.target,
+            keep_going: self.keep_going,
+            split_gaps: self.split_gaps,
+            max_gap: self.max_gap,
+            disabled_features: wasm_squeeze::OPTIONAL_FEATURES
+                .iter()
+                .filter(|(name, _)| {
+                    self.disable_feature
+                        .iter()
+                        .any(|disabled| disabled.as_str() == *name)
+                })
+                .fold(Default::default(), |features, &(_, feature)| {
+                    features | feature
+                }),
+            bench_unpack: self.bench_unpack,
+            verify_exec: self.verify_exec,
+            compress_globals: self.compress_globals,
+            unpacker_import: self
+                .unpacker_as_import
+                .as_deref()
+                .map(|names| (names[0].clone(), names[1].clone())),
+            in_place: self.in_place,
+            merge_fallback: self.merge_fallback,
+            packer: PackerConfig {
+                parity_contexts: self.parity_contexts,
+                invert_bit_encoding: self.invert_bit_encoding,
+                simplified_prob_update: self.simplified_prob_update,
+                bitstream: self.bitstream,
+            },
+            custom_unpacker,
+            reserved: self.reserve.clone(),
+            strip: self.strip,
+            strip_custom: self.strip_custom.clone(),
+            resqueeze: self.resqueeze,
+            best_effort: self.best_effort,
+            assert_roundtrip: self.assert_roundtrip,
+        })
+    }
 }
 
-#[derive(Clone, Copy)]
-struct Data<D> {
-    offset: i32,
-    data: D,
+/// Parse a `start..end` memory range of decimal or `0x` prefixed hexadecimal addresses
+fn parse_range(range: &str) -> anyhow::Result<Range<i32>> {
+    let (start, end) = range.split_once("..").context("expected `START..END`")?;
+    let parse = |address: &str| match address.strip_prefix("0x") {
+        Some(hex) => i32::from_str_radix(hex, 16),
+        None => address.parse(),
+    };
+    let range = parse(start)?..parse(end)?;
+    anyhow::ensure!(range.start <= range.end, "range starts past its end");
+    Ok(range)
 }
 
-impl fmt::Debug for Data<Vec<u8>> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Data")
-            .field("offset", &self.offset)
-            .field("data", &format_args!("[u8; {}]", self.data.len()))
-            .finish()
-    }
+/// Remove items of the `module` unreachable from its exports and its start function
+fn gc(module: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut module = walrus::ModuleConfig::new()
+        .generate_producers_section(false)
+        .parse(module)?;
+    walrus::passes::gc::run(&mut module);
+    Ok(module.emit_wasm())
 }
 
-impl<T: fmt::Debug> fmt::Debug for Data<Range<T>> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Data")
-            .field("offset", &self.offset)
-            .field(
-                "data",
-                &format_args!("{:?}..{:?}", self.data.start, self.data.end),
-            )
-            .finish()
-    }
+/// Run `wasm-opt` with the `args` separated by whitespace on the `module`
+fn wasm_opt(module: &[u8], args: &str) -> anyhow::Result<Vec<u8>> {
+    let program = env::var_os("WASM_OPT").unwrap_or_else(|| "wasm-opt".into());
+    let mut command = process::Command::new(&program);
+    command.args(args.split_whitespace()).args(["-", "-o", "-"]);
+    pipe_through(&mut command, module)
+        .with_context(|| format!("running `{} {args}`", program.to_string_lossy()))
 }
 
-impl Data<Range<usize>> {
-    fn parse_slice<'a>(&self, module: &'a [u8]) -> anyhow::Result<Data<&'a [u8]>> {
-        let mut reader =
-            wp::BinaryReader::new(&module[self.data.clone()], self.data.start, WASM_FEATURES);
-        let data = wp::Data::from_reader(&mut reader)?;
+/// Run the `command` with `data` on its stdin, returning its stdout
+fn pipe_through(command: &mut process::Command, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut child = command
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().unwrap();
+    // Data is written from another thread so that the command never blocks on full stdout
+    let output = thread::scope(|scope| {
+        let writer = scope.spawn(move || stdin.write_all(data));
+        let output = child.wait_with_output();
+        writer.join().expect("writing thread panicked")?;
+        output
+    })?;
+    anyhow::ensure!(
+        output.status.success(),
+        "command failed with {}",
+        output.status
+    );
+    Ok(output.stdout)
+}
 
-        #[cfg(debug_assertions)]
-        {
-            let wp::DataKind::Active {
-                memory_index,
-                offset_expr,
-            } = data.kind
-            else {
-                panic!("parsed data kind mismatch")
-            };
-            debug_assert_eq!(memory_index, 0, "multimemory is not supported");
-            debug_assert_eq!(
-                eval_i32(&offset_expr).context("evaluating data offset")?,
-                self.offset,
-                "parsed data offset mismatch"
-            );
-        }
+/// Packer running an external command
+struct CommandPacker {
+    /// Program followed by its arguments, separated by whitespace
+    command: String,
+}
 
-        Ok(Data {
-            data: data.data,
-            offset: self.offset,
-        })
+impl Packer for CommandPacker {
+    fn pack(&self, data: &[u8], level: u8) -> anyhow::Result<Vec<u8>> {
+        let mut words = self.command.split_whitespace();
+        let program = words.next().context("pack command is empty")?;
+        let mut command = process::Command::new(program);
+        command
+            .args(words)
+            .env("WASM_SQUEEZE_LEVEL", level.to_string());
+        pipe_through(&mut command, data)
+            .with_context(|| format!("piping data through `{}`", self.command))
     }
 }
 
-impl Data<&[u8]> {
-    fn to_vec(&self) -> Data<Vec<u8>> {
-        Data {
-            offset: self.offset,
-            data: self.data.to_owned(),
-        }
+fn open_input(path: &Path) -> io::Result<Box<dyn io::Read>> {
+    Ok(if path == Path::new("-") {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(io::BufReader::new(File::open(path)?))
+    })
+}
+
+/// Map the regular file at `path` into memory, or return `None` for other files like pipes
+fn map_file(path: &Path) -> anyhow::Result<Option<memmap2::Mmap>> {
+    let file = File::open(path).context("opening input")?;
+    if !file.metadata().context("reading input metadata")?.is_file() {
+        return Ok(None);
     }
+    // SAFETY: the input must not be modified while it is squeezed, just like with other tools
+    // reading it in parts
+    let map = unsafe { memmap2::Mmap::map(&file) }.context("mapping input")?;
+    Ok(Some(map))
 }
 
-impl RelevantInfo {
-    fn unpacker_reencoder(&self) -> AdaptUnpacker {
-        AdaptUnpacker {
-            functions_index_base: self.old_function_count + self.import_function_count,
-            types_index_base: self.old_type_count,
+/// Print a matrix of squeezing requirements
+fn check(input: Box<dyn io::Read>) -> anyhow::Result<()> {
+    let mut failed = false;
+    for requirement in wasm_squeeze::check(input) {
+        let description = requirement.description;
+        match requirement.status {
+            RequirementStatus::Passed(note) if note.is_empty() => println!("PASS  {description}"),
+            RequirementStatus::Passed(note) => println!("PASS  {description}: {note}"),
+            RequirementStatus::Failed(err) => {
+                failed = true;
+                println!("FAIL  {description}: {err:#}")
+            }
+            RequirementStatus::Skipped => println!("SKIP  {description}"),
         }
     }
+    anyhow::ensure!(!failed, "some requirements are not met");
+    Ok(())
 }
 
-struct RelevantInfoBuilder {
-    start_fn_idx: Option<u32>,
-    data: Vec<Data<Range<usize>>>,
-    old_functions: Option<Vec<u32>>,
-    old_type_count: Option<u32>,
-    import_function_count: Option<u32>,
-    data_count_range: Option<Range<usize>>,
+/// Command line definition, with the long help of the `targets` subcommand generated from the
+/// registered profiles
+fn command() -> clap::Command {
+    Args::command().mut_subcommand("targets", |command| {
+        command.long_about(format!(
+            "Runtimes squeezed modules may target\n\n{}",
+            targets_help()
+        ))
+    })
 }
 
-impl RelevantInfoBuilder {
-    fn new() -> Self {
-        Self {
-            start_fn_idx: None,
-            data: Vec::new(),
-            old_functions: None,
-            old_type_count: None,
-            import_function_count: None,
-            data_count_range: None,
+/// Describe every target runtime with the registers the prologue resets
+fn targets_help() -> String {
+    let mut help = String::new();
+    for target in Target::ALL {
+        let profile = target.profile();
+        help += &format!("{:<10} {}\n", profile.name, profile.description);
+        for register in profile.registers {
+            let value = match register.value {
+                RegisterValue::I16(value) => format!("{value:#06x}"),
+                RegisterValue::I32(value) => format!("{value:#010x}"),
+                RegisterValue::I64(value) => format!("{value:#018x}"),
+            };
+            help += &format!(
+                "{:<10} register at {:#06x} = {value}\n",
+                "", register.offset
+            );
         }
     }
+    help
+}
 
-    fn add_payload(&mut self, payload: wp::Payload) -> anyhow::Result<()> {
-        match payload {
-            wp::Payload::DataCountSection { count, range } => {
-                if count != 1 {
-                    anyhow::ensure!(
-                        self.data_count_range.is_none(),
-                        "encountered multiple data count sections"
-                    );
-
-                    self.data_count_range = Some(range);
-                }
-            }
-            wp::Payload::DataSection(data) => {
-                anyhow::ensure!(self.data.is_empty(), "encountered multiple data sections");
-                self.data.reserve(data.count().try_into()?);
-                for data in data {
-                    let data = data?;
-                    let wp::DataKind::Active {
-                        memory_index,
-                        offset_expr,
-                    } = &data.kind
-                    else {
-                        continue;
-                    };
-                    anyhow::ensure!(*memory_index == 0, "multi memory is not supported");
-                    let offset =
-                        eval_i32(&offset_expr).context("evaluating a data offset expression")?;
-                    self.data.push(Data {
-                        data: data.range,
-                        offset,
-                    })
-                }
-            }
-            wp::Payload::ImportSection(imports) => {
-                anyhow::ensure!(
-                    self.import_function_count.is_none(),
-                    "encountered multiple import sections"
-                );
-                anyhow::ensure!(
-                    self.old_functions.is_none(),
-                    "encountered imports after the function section"
-                );
-                let mut import_function_count = 0;
-                for import in imports {
-                    let import = import?;
-                    if let wp::TypeRef::Func(_) = import.ty {
-                        import_function_count += 1;
-                    }
-                }
-                self.import_function_count = Some(import_function_count);
-            }
-            wp::Payload::FunctionSection(functions) => {
-                anyhow::ensure!(
-                    self.old_functions.is_none(),
-                    "encountered multiple function sections"
-                );
-                self.old_functions = Some(functions.into_iter().collect::<Result<_, _>>()?);
-            }
-            wp::Payload::TypeSection(types) => {
-                anyhow::ensure!(
-                    self.old_type_count.is_none(),
-                    "encountered multiple type sections"
-                );
-                self.old_type_count = Some(types.count());
-            }
-            wp::Payload::StartSection { func, .. } => {
-                anyhow::ensure!(self.start_fn_idx.is_none(), "found multiple start sections");
-                self.start_fn_idx = Some(func);
-            }
-            _ => {}
-        }
-        Ok(())
+/// Summarize the `outcome` as a JSON object of the `api_version` format
+fn metrics_json(outcome: &SqueezeOutcome, level: u8, api_version: u32) -> String {
+    fn json<T: ToString>(value: Option<T>) -> String {
+        value.map_or_else(|| "null".to_owned(), |value| value.to_string())
     }
 
-    /// Return info and modified input with mitigations like edited data count section
-    fn build(mut self, input: &[u8]) -> anyhow::Result<(RelevantInfo, Vec<u8>)> {
-        if self.data.is_empty() {
-            return Err(NoDataError.into());
+    // Bytes added besides the compressed data, mostly the unpacker and the prologue
+    let unpacker_overhead = match (outcome.data_size, outcome.packed_data_size) {
+        (Some(data_size), Some(packed_data_size)) if outcome.compression_skipped.is_none() => {
+            outcome.candidate_size.map(|candidate_size| {
+                candidate_size as isize + data_size as isize
+                    - packed_data_size as isize
+                    - outcome.input_size as isize
+            })
         }
+        _ => None,
+    };
+    let fields = [
+        ("api_version", api_version.to_string()),
+        ("input_size", outcome.input_size.to_string()),
+        ("output_size", outcome.output.len().to_string()),
+        ("candidate_size", json(outcome.candidate_size)),
+        ("data_size", json(outcome.data_size)),
+        ("packed_data_size", json(outcome.packed_data_size)),
+        ("unpacker_overhead", json(unpacker_overhead)),
+        (
+            "init_byte_ratio",
+            json(
+                Option::zip(outcome.init_data_size, outcome.data_size)
+                    // NaN is not valid JSON
+                    .filter(|&(_, data_size)| data_size != 0)
+                    .map(|(init_size, data_size)| init_size as f64 / data_size as f64),
+            ),
+        ),
+        ("level", level.to_string()),
+        (
+            "passthrough_reason",
+            json(
+                outcome
+                    .passthrough_reason
+                    .map(|reason| json_string(&reason.to_string())),
+            ),
+        ),
+    ];
+    let fields: Vec<_> = fields
+        .iter()
+        .map(|(name, value)| format!("\"{name}\":{value}"))
+        .collect();
+    format!("{{{}}}", fields.join(","))
+}
 
-        let mut input = input.to_owned();
-
-        if let Some(range) = self.data_count_range {
-            // replacing value for the input buffer data count, which is stored as LEB128
-            let varint = input
-                .get_mut(range)
-                .context("invalid range for data count sections")?;
-            match varint {
-                [] => anyhow::bail!("data count range is empty"),
-                [byte] => *byte = 1,
-                [first, middle @ .., last] => {
-                    *first = 0x81;
-                    middle.fill(0x80);
-                    *last = 0;
-                }
-            }
-        }
-
-        // zero sized data is't supported
-        self.data.sort_unstable_by_key(|d| d.offset);
-
-        // Merge data sections
-        let mut data = self.data.iter();
-        let first_data = data.next().unwrap().parse_slice(&input)?;
-        let mut init_bytes = first_data.data.len();
-        let mut output_data = first_data.to_vec();
-
-        for data in data {
-            let data = data.parse_slice(&input)?;
-            init_bytes += data.data.len();
-            let new_len = (data.offset - output_data.offset) as usize;
-            anyhow::ensure!(output_data.data.len() <= new_len, "data sections overlap");
-            output_data.data.resize(new_len, 0);
-            output_data.data.extend_from_slice(data.data);
+/// Quote the `value` as a JSON string
+fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c < ' ' => quoted.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => quoted.push(c),
         }
-        log::info!(
-            "Data section's memory has {:.2}% of initialized bytes",
-            100.0 * init_bytes as f64 / output_data.data.len() as f64
-        );
-
-        let old_functions = self
-            .old_functions
-            .context("no function section encountered")?;
-        Ok((
-            RelevantInfo {
-                old_function_count: old_functions.len().try_into().unwrap(),
-                import_function_count: self.import_function_count.unwrap_or(0),
-                old_type_count: self.old_type_count.context("no type section was found")?,
-                start_fn_idx: self.start_fn_idx,
-                data: output_data,
-            },
-            input,
-        ))
     }
+    quoted.push('"');
+    quoted
 }
 
-#[derive(Debug)]
-struct NoDataError;
-
-impl fmt::Display for NoDataError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        "no data to compress".fmt(f)
+/// Print what this build supports as text or JSON
+fn capabilities(json: bool) {
+    let capabilities = wasm_squeeze::capabilities();
+    let targets: Vec<_> = capabilities.targets.iter().map(Target::to_string).collect();
+    let lists = [
+        ("codecs", capabilities.codecs.to_vec()),
+        ("targets", targets.iter().map(String::as_str).collect()),
+        ("optional_features", capabilities.optional_features),
+    ];
+    let flags = [
+        ("bench", capabilities.bench),
+        ("host_upkr", capabilities.host_upkr),
+        ("bundle", capabilities.bundle),
+        ("verify_exec", capabilities.verify_exec),
+    ];
+    if json {
+        let lists = lists.iter().map(|(name, values)| {
+            let values: Vec<_> = values.iter().map(|value| json_string(value)).collect();
+            format!("\"{name}\":[{}]", values.join(","))
+        });
+        let flags = flags
+            .iter()
+            .map(|(name, enabled)| format!("\"{name}\":{enabled}"));
+        let fields: Vec<_> = iter::once(format!("\"api_version\":{API_VERSION}"))
+            .chain(lists)
+            .chain(flags)
+            .collect();
+        println!("{{{}}}", fields.join(","));
+    } else {
+        for (name, values) in &lists {
+            println!("{name}: {}", values.join(", "));
+        }
+        for (name, enabled) in flags {
+            println!("{name}: {}", if enabled { "yes" } else { "no" });
+        }
     }
 }
 
-impl Error for NoDataError {}
-
-struct UnpackerComponents<'a> {
-    types: wp::TypeSectionReader<'a>,
-    functions: wp::FunctionSectionReader<'a>,
-    function_bodies: Vec<wp::FunctionBody<'a>>,
-    unpack_fn_idx: u32,
-}
-
-impl<'a> UnpackerComponents<'a> {
-    fn parse() -> Self {
-        let data = UNPACKER_WASM;
-        let mut types = None;
-        let mut functions = None;
-        let mut function_bodies = Vec::new();
-        let mut parser = wp::Parser::new(0);
-        let mut unpack_fn_idx = None;
-        parser.set_features(WASM_FEATURES);
-
-        for payload in parser.parse_all(data) {
-            match payload.unwrap() {
-                wp::Payload::TypeSection(t) => {
-                    assert!(types.is_none(), "multiple type sections found");
-                    types = Some(t);
-                }
-                wp::Payload::FunctionSection(f) => {
-                    assert!(functions.is_none(), "multiple function sections found");
-                    functions = Some(f);
-                }
-                wp::Payload::CodeSectionStart { count, .. } => {
-                    function_bodies.reserve(count.try_into().unwrap())
-                }
-                wp::Payload::CodeSectionEntry(function) => function_bodies.push(function),
-                wp::Payload::ExportSection(exports) => {
-                    let mut exports = exports.into_iter();
-                    let export = exports.next().unwrap().unwrap();
-                    assert!(unpack_fn_idx.is_none());
-                    unpack_fn_idx = Some(export.index);
-                    assert!(exports.next().is_none());
+/// Squeeze modules of the `corpus` directory with each of the `configs` and print the results
+fn bench(corpus: &Path, configs: &[String]) -> anyhow::Result<()> {
+    let default_config = [String::new()];
+    let configs = if configs.is_empty() {
+        &default_config
+    } else {
+        configs
+    };
+    let options = configs
+        .iter()
+        .map(|config| {
+            let argv = iter::once("wasm-squeeze").chain(config.split_whitespace());
+            let args = Args::from_arg_matches(&command().try_get_matches_from(argv)?)?;
+            args.squeeze_options()
+        })
+        .collect::<anyhow::Result<Vec<_>>>()
+        .context("parsing a configuration")?;
+    let mut paths: Vec<_> = std::fs::read_dir(corpus)
+        .context("reading the corpus directory")?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    paths.retain(|path| path.extension() == Some("wasm".as_ref()));
+    paths.sort();
+
+    println!(
+        "{:<32} {:<24} {:>8} {:>8} {:>7} {:>10}",
+        "file", "config", "input", "output", "ratio", "time"
+    );
+    // Total input and output sizes and time of every configuration
+    let mut totals = vec![(0, 0, Duration::ZERO); configs.len()];
+    let mut passed_through = false;
+    for path in &paths {
+        let input = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        for ((config, options), total) in iter::zip(configs, &options).zip(&mut totals) {
+            let start = Instant::now();
+            let result = wasm_squeeze::squeeze_reader(&input[..], options);
+            let time = start.elapsed();
+            let config = if config.is_empty() { "default" } else { config };
+            let outcome = match result {
+                Ok(outcome) => outcome,
+                Err(err) => {
+                    println!("{name:<32} {config:<24} failed: {err:#}");
+                    continue;
                 }
-                _ => (),
-            }
-        }
-        UnpackerComponents {
-            types: types.unwrap(),
-            functions: functions.unwrap(),
-            unpack_fn_idx: unpack_fn_idx.unwrap(),
-            function_bodies,
+            };
+            let output_len = outcome.output.len();
+            // Passed through inputs are marked to tell them from barely squeezed ones
+            passed_through |= outcome.passthrough_reason.is_some();
+            let mark = if outcome.passthrough_reason.is_some() {
+                "*"
+            } else {
+                ""
+            };
+            println!(
+                "{name:<32} {config:<24} {:>8} {:>8} {:>6.2}% {:>10.2?}{mark}",
+                input.len(),
+                output_len,
+                100.0 * output_len as f64 / input.len() as f64,
+                time
+            );
+            total.0 += input.len();
+            total.1 += output_len;
+            total.2 += time;
         }
     }
+    for (config, (input_len, output_len, time)) in iter::zip(configs, totals) {
+        let config = if config.is_empty() { "default" } else { config };
+        println!(
+            "{:<32} {config:<24} {input_len:>8} {output_len:>8} {:>6.2}% {time:>10.2?}",
+            "total",
+            100.0 * output_len as f64 / input_len.max(1) as f64,
+        );
+    }
+    if passed_through {
+        println!("* input was passed through");
+    }
+    Ok(())
 }
 
-fn reencode_with_unpacker<'a>(
-    input_module: &[u8],
-    info: RelevantInfo,
-    unpacker: UnpackerComponents<'a>,
-    compression_level: u8,
-) -> anyhow::Result<we::Module> {
-    let mut module = we::Module::new();
+/// Print a report of section sizes and data statistics
+fn inspect(input: Box<dyn io::Read>, target: Target) -> anyhow::Result<()> {
+    let inspection = wasm_squeeze::inspect(input)?;
+    println!("Sections:");
+    for (name, size) in &inspection.sections {
+        println!("  {name:<24} {size:>8} bytes");
+    }
 
-    let packed_data = upkr::pack(
-        &info.data.data,
-        compression_level,
-        &upkr::Config::default(),
-        None,
+    println!("Data segments: {}", inspection.data_segments.len());
+    for (offset, len) in &inspection.data_segments {
+        match offset {
+            Some(offset) => println!(
+                "  {offset:<#24x} {len:>8} bytes{}",
+                region_note(target, *offset, *len)
+            ),
+            None => println!("  {:<24} {len:>8} bytes", "passive or relative"),
+        }
+    }
+    let data_len: usize = inspection.data_segments.iter().map(|(_, len)| len).sum();
+    println!(
+        "Data entropy: {:.2} bits per byte, about {:.0} bytes compressed byte by byte",
+        inspection.data_entropy,
+        data_len as f64 * inspection.data_entropy / 8.0
     );
-    let packed_data = if info.data.data.len() <= packed_data.len() {
-        log::warn!("Could not compress data into less bytes, writing old");
-        None
-    } else if usize::try_from(MEM_SIZE).unwrap()
-        < packed_data.len() + usize::try_from(common::CONTEXT_SIZE).unwrap() + info.data.data.len()
-    {
-        log::warn!("Decompression requires more than 64KiB space, writing old");
-        None
-    } else {
-        Some(packed_data)
-    };
 
-    let mut merger = Merger {
-        function_bodies_left: info.old_function_count,
-        unpack_fn_idx: info.import_function_count
-            + info.old_function_count
-            + unpacker.unpack_fn_idx,
-        subroutine_fn_type_idx: info.old_type_count + unpacker.types.count(),
-        new_start_fn_idx: info.start_fn_idx.unwrap_or_else(|| {
-            info.import_function_count + info.old_function_count + unpacker.functions.count()
-        }),
-        info,
-        packed_data,
-        unpacker,
-    };
-    merger.parse_core_module(&mut module, wp::Parser::new(0), input_module)?;
-
-    return Ok(module);
-
-    struct Merger<'a> {
-        info: RelevantInfo,
-        unpacker: UnpackerComponents<'a>,
-        function_bodies_left: u32,
-        subroutine_fn_type_idx: u32,
-        new_start_fn_idx: u32,
-        unpack_fn_idx: u32,
-        packed_data: Option<Vec<u8>>,
-    }
-
-    impl<'a> Reencode for Merger<'a> {
-        type Error = io::Error;
-
-        fn parse_type_section(
-            &mut self,
-            types: &mut we::TypeSection,
-            section: wp::TypeSectionReader<'_>,
-        ) -> Result<(), reencode::Error<Self::Error>> {
-            reencode::utils::parse_type_section(self, types, section)?;
-            assert_eq!(types.len(), self.info.old_type_count);
-            reencode::utils::parse_type_section(
-                &mut self.info.unpacker_reencoder(),
-                types,
-                self.unpacker.types.clone(),
-            )?;
-            assert_eq!(types.len(), self.subroutine_fn_type_idx);
-            types.function(iter::empty(), iter::empty());
-            Ok(())
-        }
-
-        fn parse_function_section(
-            &mut self,
-            functions: &mut we::FunctionSection,
-            section: wp::FunctionSectionReader<'_>,
-        ) -> Result<(), reencode::Error<Self::Error>> {
-            reencode::utils::parse_function_section(self, functions, section)?;
-            assert_eq!(functions.len(), self.info.old_function_count);
-            reencode::utils::parse_function_section(
-                &mut self.info.unpacker_reencoder(),
-                functions,
-                self.unpacker.functions.clone(),
-            )?;
-            if self.info.start_fn_idx.is_none() {
-                assert_eq!(
-                    self.info.import_function_count + functions.len(),
-                    self.new_start_fn_idx
+    match inspection.squeeze {
+        Ok(outcome) => match outcome.passthrough_reason {
+            Some(reason) => println!("Squeezing would pass through the input: {reason}"),
+            None => {
+                let reduced_bytes = outcome.input_size as isize - outcome.output.len() as isize;
+                println!(
+                    "Squeezing would reduce size by {} bytes ({:.2}%)",
+                    reduced_bytes,
+                    100.0 * reduced_bytes as f64 / outcome.input_size as f64
                 );
-                functions.function(self.subroutine_fn_type_idx);
-            }
-            Ok(())
-        }
-
-        fn parse_function_body(
-            &mut self,
-            code: &mut we::CodeSection,
-            func: wp::FunctionBody<'_>,
-        ) -> Result<(), reencode::Error<Self::Error>> {
-            if Some(self.info.import_function_count + code.len()) != self.info.start_fn_idx
-                && self.packed_data.is_some()
-            {
-                reencode::utils::parse_function_body(self, code, func)?;
-            } else {
-                let mut f = self.new_function_with_parsed_locals(&func)?;
-                self.encode_prefix_instrs(&mut f);
-                let mut reader = func.get_operators_reader()?;
-                while !reader.eof() {
-                    self.parse_instruction(&mut f, &mut reader)?;
-                }
-                code.function(&f);
-            }
-            self.function_bodies_left -= 1;
-            if self.function_bodies_left == 0 {
-                // Last function body parsed
-                assert_eq!(code.len(), self.info.old_function_count);
-                let mut unpacker_reencoder = self.info.unpacker_reencoder();
-                for func in &self.unpacker.function_bodies {
-                    reencode::utils::parse_function_body(
-                        &mut unpacker_reencoder,
-                        code,
-                        func.clone(),
-                    )?;
-                }
-                if self.info.start_fn_idx.is_none() && self.packed_data.is_some() {
-                    assert_eq!(
-                        self.info.import_function_count + code.len(),
-                        self.new_start_fn_idx
-                    );
-                    let mut func = we::Function::new(iter::empty());
-                    self.encode_prefix_instrs(&mut func);
-                    func.instruction(&we::Instruction::End);
-                    code.function(&func);
-                }
-            }
-            Ok(())
-        }
-
-        fn parse_data_section(
-            &mut self,
-            data: &mut we::DataSection,
-            _section: wp::DataSectionReader<'_>,
-        ) -> Result<(), reencode::Error<Self::Error>> {
-            if let Some(packed) = self.packed_data.as_deref() {
-                let offset = we::ConstExpr::i32_const(COMPRESSED_DATA_OFFSET);
-                data.active(0, &offset, packed.iter().copied());
-            } else {
-                let offset = we::ConstExpr::i32_const(self.info.data.offset as i32);
-                data.active(0, &offset, self.info.data.data.iter().copied());
-            }
-            Ok(())
-        }
-
-        fn intersperse_section_hook(
-            &mut self,
-            module: &mut we::Module,
-            after: Option<we::SectionId>,
-            _before: Option<we::SectionId>,
-        ) -> Result<(), reencode::Error<Self::Error>> {
-            if after == Some(we::SectionId::Export) && self.info.start_fn_idx.is_none() {
-                module.section(&we::StartSection {
-                    function_index: self.new_start_fn_idx,
-                });
-            }
-            Ok(())
-        }
-    }
-
-    impl<'a> Merger<'a> {
-        fn encode_prefix_instrs(&mut self, func: &mut we::Function) {
-            let original_data_len = self.info.data.data.len().try_into().unwrap();
-            let destination_offset = MEM_SIZE.checked_sub(original_data_len).unwrap();
-            let original_data_offset = self.info.data.offset.try_into().unwrap();
-            assert!(destination_offset >= 0);
-
-            func.instruction(&we::Instruction::I32Const(CONTEXT_OFFSET))
-                .instruction(&we::Instruction::I32Const(destination_offset))
-                .instruction(&we::Instruction::I32Const(COMPRESSED_DATA_OFFSET))
-                .instruction(&we::Instruction::Call((&mut *self).unpack_fn_idx))
-                .instruction(&we::Instruction::Drop);
-
-            func.instruction(&we::Instruction::I32Const(original_data_offset))
-                .instruction(&we::Instruction::I32Const(destination_offset))
-                .instruction(&we::Instruction::I32Const(original_data_len))
-                .instruction(&we::Instruction::MemoryCopy {
-                    src_mem: 0,
-                    dst_mem: 0,
-                });
-
-            func.instruction(&we::Instruction::I32Const(0))
-                .instruction(&we::Instruction::I32Const(0))
-                .instruction(&we::Instruction::I32Const(original_data_offset))
-                .instruction(&we::Instruction::MemoryFill(0));
-
-            let original_data_end = original_data_offset + original_data_len;
-            func.instruction(&we::Instruction::I32Const(original_data_end))
-                .instruction(&we::Instruction::I32Const(0))
-                .instruction(&we::Instruction::I32Const(MEM_SIZE - original_data_end))
-                .instruction(&we::Instruction::MemoryFill(0));
-
-            for (i, &palette_chunk) in PALETTE_DEFAULT.iter().enumerate() {
-                func.instruction(&we::Instruction::I32Const(PALETTE_OFFSET + 8 * i as i32))
-                    .instruction(&we::Instruction::I64Const(palette_chunk))
-                    .instruction(&we::Instruction::I64Store(we::MemArg {
-                        offset: 0,
-                        align: 2,
-                        memory_index: 0,
-                    }));
             }
+        },
+        Err(err) => println!("Squeezing would fail: {err:#}"),
+    }
+    Ok(())
+}
 
-            func.instruction(&we::Instruction::I32Const(DRAW_COLORS_OFFSET))
-                .instruction(&we::Instruction::I32Const(DRAW_COLORS_DEFAULT.into()))
-                .instruction(&we::Instruction::I32Store16(we::MemArg {
-                    offset: 0,
-                    align: 1,
-                    memory_index: 0,
-                }));
+/// Names of the `target`'s memory regions overlapping `len` bytes at `offset` in parentheses,
+/// or nothing if the target has no memory map
+fn region_note(target: Target, offset: u64, len: usize) -> String {
+    let start = i32::try_from(offset).unwrap_or(i32::MAX);
+    let end = start.saturating_add(len.try_into().unwrap_or(i32::MAX));
+    let names: Vec<_> = target.profile().region_names(start..end).collect();
+    match names.is_empty() {
+        true => String::new(),
+        false => format!(" ({})", names.join(", ")),
+    }
+}
 
-            func.instruction(&we::Instruction::I32Const(MOUSE_XY_OFFSET))
-                .instruction(&we::Instruction::I32Const(MOUSE_XY_DEFAULT))
-                .instruction(&we::Instruction::I32Store(we::MemArg {
-                    offset: 0,
-                    align: 1,
-                    memory_index: 0,
-                }));
-        }
+/// Write what is needed to reproduce an internal error into the `dir`
+fn save_crash_bundle(
+    dir: &Path,
+    input: &[u8],
+    no_input: bool,
+    result: &anyhow::Result<(SqueezeOutcome, u8)>,
+) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dir)?;
+    if no_input {
+        let inspection = wasm_squeeze::inspect(input)?;
+        let sections = inspection
+            .sections
+            .iter()
+            .map(|(name, size)| format!("section {name} {size}\n"));
+        let data_segments = inspection
+            .data_segments
+            .iter()
+            .map(|(offset, len)| match offset {
+                Some(offset) => format!("data {offset:#x} {len}\n"),
+                None => format!("data - {len}\n"),
+            });
+        let structure: String = sections.chain(data_segments).collect();
+        std::fs::write(dir.join("structure.txt"), structure)?;
+    } else {
+        std::fs::write(dir.join("input.wasm"), input)?;
     }
+
+    let command_line: Vec<_> = std::env::args_os()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect();
+    let outcome = match result {
+        Ok((outcome, level)) => format!(
+            "Passed through at level {level}\n\
+             Input size: {}\n\
+             Data size: {:?}\n\
+             Candidate size: {:?}\n\
+             Packed data size: {:?}\n",
+            outcome.input_size, outcome.data_size, outcome.candidate_size, outcome.packed_data_size
+        ),
+        Err(err) => format!("Error: {err:?}\n"),
+    };
+    let report = format!(
+        "wasm-squeeze {}\nCommand line: {}\n{outcome}",
+        env!("CARGO_PKG_VERSION"),
+        command_line.join(" ")
+    );
+    std::fs::write(dir.join("report.txt"), report)?;
+    Ok(())
 }
 
-struct AdaptUnpacker {
-    functions_index_base: u32,
-    types_index_base: u32,
+/// Rust source defining the `module` as a byte slice constant
+fn rs_embed(module: &[u8]) -> String {
+    let mut source = String::from("// Generated by wasm-squeeze\npub const CART: &[u8] = &[\n");
+    source += &byte_lines(module);
+    source += "];\n";
+    source
 }
 
-impl Reencode for AdaptUnpacker {
-    type Error = io::Error;
+/// C header defining the `module` as a byte array with its size
+fn c_embed(module: &[u8]) -> String {
+    let mut source = format!(
+        "// Generated by wasm-squeeze\n\
+         #define CART_SIZE {}\n\
+         static const unsigned char cart[] = {{\n",
+        module.len()
+    );
+    source += &byte_lines(module);
+    source += "};\n";
+    source
+}
 
-    fn type_index(&mut self, ty: u32) -> u32 {
-        ty.checked_add(self.types_index_base)
-            .expect("too many types")
+/// Indented hex literals of the `bytes`, 16 per line
+fn byte_lines(bytes: &[u8]) -> String {
+    let mut lines = String::new();
+    for line in bytes.chunks(16) {
+        let line: Vec<_> = line.iter().map(|byte| format!("{byte:#04x},")).collect();
+        lines += &format!("    {}\n", line.join(" "));
     }
+    lines
+}
 
-    fn function_index(&mut self, func: u32) -> u32 {
-        func.checked_add(self.functions_index_base)
-            .expect("too many functions")
-    }
+fn write_output(path: &Path, output: &[u8]) -> Result<(), anyhow::Error> {
+    Ok(if path == Path::new("-") {
+        anyhow::ensure!(
+            !io::stdout().is_terminal(),
+            "stdout is a terminal, cannot print the output wasm binary file"
+        );
+        io::stdout().lock().write_all(output)?;
+    } else {
+        std::fs::write(path, output)?;
+    })
 }
 
-fn eval_i32(expr: &wp::ConstExpr) -> anyhow::Result<i32> {
-    let mut reader = expr.get_operators_reader();
-    let wp::Operator::I32Const { value } = reader.read()? else {
-        anyhow::bail!("Expected expression to be a single `I32Const`");
-    };
-    anyhow::ensure!(
-        matches!(reader.read()?, wp::Operator::End),
-        "Expression has unexpected succeeding operators"
-    );
-    Ok(value as i32)
+/// Replace the input file at `path` with the `output` through a temporary file, which keeps the
+/// mapped input intact until the output is complete
+fn replace_input(path: &Path, output: &[u8]) -> anyhow::Result<()> {
+    let name = path.file_name().context("input path has no file name")?;
+    let temp = path.with_file_name(format!(".{}.tmp", name.to_string_lossy()));
+    let result = std::fs::write(&temp, output).and_then(|()| std::fs::rename(&temp, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    Ok(result?)
 }
@@ -0,0 +1,220 @@
+//! Streaming parsing of wasm modules from readers
+
+use std::{error::Error, fmt, io, thread, time::Duration};
+
+use anyhow::Context;
+use wasmparser as wp;
+
+/// Largest input accepted, matching the parser's own limit on module size
+const MAX_INPUT_LEN: usize = 1 << 30;
+/// Largest read at once, so huge sizes declared by the input are not allocated upfront
+const READ_CHUNK_LEN: usize = 0x10000;
+/// Delay before polling a non-blocking reader which has no data yet again
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Read a wasm module from the `reader` chunk by chunk, passing every parsed payload to the
+/// `visitor`, and return all of the read bytes.
+///
+/// Unlike [`wp::Parser::parse_all`] this does not require the whole module to be read upfront.
+/// Short reads are fine and non-blocking readers are polled until they have data.
+pub fn walk<R, F>(
+    mut reader: R,
+    features: wp::WasmFeatures,
+    mut visitor: F,
+) -> anyhow::Result<Vec<u8>>
+where
+    R: io::Read,
+    F: FnMut(wp::Payload) -> anyhow::Result<()>,
+{
+    let mut input_buffer = Vec::new();
+
+    let mut consumed_bytes = 0;
+    let mut eof = false;
+    let mut parser = wp::Parser::new(0);
+    parser.set_features(features);
+
+    loop {
+        let chunk = match parser.parse(&input_buffer[consumed_bytes..], eof) {
+            Ok(chunk) => chunk,
+            Err(err) if consumed_bytes == 0 => match ForeignFormat::sniff(&input_buffer) {
+                Some(format) => return Err(NotWasmError(format).into()),
+                None => return Err(err.into()),
+            },
+            Err(err) => return Err(err.into()),
+        };
+
+        let payload = match chunk {
+            wp::Chunk::NeedMoreData(more_bytes) => {
+                let len = input_buffer.len();
+                anyhow::ensure!(
+                    len < MAX_INPUT_LEN,
+                    "input is larger than {MAX_INPUT_LEN} bytes"
+                );
+                // Parser asks again if the read falls short of what it needs
+                let more_bytes = usize::try_from(more_bytes)
+                    .unwrap_or(usize::MAX)
+                    .clamp(1, READ_CHUNK_LEN);
+                input_buffer.resize(len + more_bytes, 0);
+                match reader.read(&mut input_buffer[len..]) {
+                    Ok(filled_bytes) => {
+                        if filled_bytes == 0 {
+                            eof = true;
+                        }
+                        input_buffer.resize_with(len + filled_bytes, || unreachable!())
+                    }
+                    Err(err) => match err.kind() {
+                        io::ErrorKind::Interrupted => {
+                            input_buffer.resize_with(len, || unreachable!())
+                        }
+                        io::ErrorKind::WouldBlock => {
+                            input_buffer.resize_with(len, || unreachable!());
+                            thread::sleep(POLL_INTERVAL);
+                        }
+                        _ => return Err(err.into()),
+                    },
+                }
+                continue;
+            }
+            wp::Chunk::Parsed { consumed, payload } => {
+                consumed_bytes += consumed;
+                payload
+            }
+        };
+
+        let is_end = matches!(payload, wp::Payload::End(_));
+        visitor(payload).context("payload `visitor` error")?;
+        if is_end {
+            break;
+        }
+    }
+
+    Ok(input_buffer)
+}
+
+/// Common format mistaken for wasm modules
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForeignFormat {
+    Png,
+    Gzip,
+    Zip,
+    /// HTML or XML, such as a `w4 bundle --html` output
+    Html,
+    /// WebAssembly text format
+    Wat,
+}
+
+impl ForeignFormat {
+    /// Recognize the format by the first bytes of the input
+    fn sniff(header: &[u8]) -> Option<Self> {
+        let text = header.trim_ascii_start();
+        Some(if header.starts_with(b"\x89PNG") {
+            ForeignFormat::Png
+        } else if header.starts_with(&[0x1f, 0x8b]) {
+            ForeignFormat::Gzip
+        } else if header.starts_with(b"PK\x03\x04") {
+            ForeignFormat::Zip
+        } else if text.starts_with(b"<") {
+            ForeignFormat::Html
+        } else if text.starts_with(b"(") || text.starts_with(b";;") {
+            ForeignFormat::Wat
+        } else {
+            return None;
+        })
+    }
+}
+
+impl fmt::Display for ForeignFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ForeignFormat::Png => "a PNG image",
+            ForeignFormat::Gzip => "a gzip archive",
+            ForeignFormat::Zip => "a zip archive",
+            ForeignFormat::Html => "an HTML or XML file",
+            ForeignFormat::Wat => "WebAssembly text",
+        }
+        .fmt(f)
+    }
+}
+
+/// Input is recognized as another format instead of a wasm module
+#[derive(Debug)]
+pub struct NotWasmError(pub ForeignFormat);
+
+impl fmt::Display for NotWasmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "input is not a wasm module, but {}", self.0)
+    }
+}
+
+impl Error for NotWasmError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reader returning a byte at a time, interrupted before every one
+    struct Trickle<'a> {
+        data: &'a [u8],
+        interrupt: bool,
+    }
+
+    impl io::Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.interrupt = !self.interrupt;
+            if self.interrupt {
+                return Err(io::ErrorKind::Interrupted.into());
+            }
+            let len = buf.len().min(self.data.len()).min(1);
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            Ok(len)
+        }
+    }
+
+    fn module() -> Vec<u8> {
+        wat::parse_str(r#"(module (memory 1) (func) (data (i32.const 8) "wasm-squeeze"))"#).unwrap()
+    }
+
+    fn payload_count(reader: impl io::Read) -> anyhow::Result<(Vec<u8>, usize)> {
+        let mut count = 0;
+        let read = walk(reader, wp::WasmFeatures::default(), |_| {
+            count += 1;
+            Ok(())
+        })?;
+        Ok((read, count))
+    }
+
+    #[test]
+    fn interrupted_single_byte_reads() {
+        let module = module();
+        let (_, expected) = payload_count(&module[..]).unwrap();
+        let reader = Trickle {
+            data: &module,
+            interrupt: false,
+        };
+        let (read, count) = payload_count(reader).unwrap();
+        assert_eq!(read, module);
+        assert_eq!(count, expected);
+    }
+
+    #[test]
+    fn truncated_module() {
+        let module = module();
+        let err = payload_count(&module[..module.len() - 1]).unwrap_err();
+        assert!(!err.is::<NotWasmError>(), "{err:?}");
+    }
+
+    #[test]
+    fn foreign_formats() {
+        let err = payload_count(&b"  (module)"[..]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<NotWasmError>(),
+            Some(NotWasmError(ForeignFormat::Wat))
+        ));
+        let err = payload_count(&b"<!DOCTYPE html>"[..]).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<NotWasmError>(),
+            Some(NotWasmError(ForeignFormat::Html))
+        ));
+    }
+}
@@ -0,0 +1,206 @@
+//! Target runtime profiles, describing state the prologue has to restore after decompression
+
+use std::{fmt, ops::Range, str::FromStr};
+
+const PALETTE_OFFSET: i32 = 4;
+const PALETTE_DEFAULT: [i64; 2] = [0x0086c06c_00e0f8cf, 0x00071821_00306850];
+const DRAW_COLORS_DEFAULT: i16 = 0x1203;
+const DRAW_COLORS_OFFSET: i32 = 0x14;
+const MOUSE_XY_DEFAULT: i32 = 0x7fff7fff;
+const MOUSE_XY_OFFSET: i32 = 0x1a;
+const MICROW8_PALETTE: Range<i32> = 0x13000..0x13400;
+const MICROW8_FONT: Range<i32> = 0x13400..0x13c00;
+
+/// Runtime the squeezed module is going to run on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Target {
+    #[default]
+    Wasm4,
+    MicroW8,
+    /// Only decompression and exact memory restoration
+    Generic,
+}
+
+impl Target {
+    pub const ALL: [Target; 3] = [Target::Wasm4, Target::MicroW8, Target::Generic];
+
+    pub fn profile(self) -> &'static TargetProfile {
+        match self {
+            Target::Wasm4 => &WASM4,
+            Target::MicroW8 => &MICROW8,
+            Target::Generic => &GENERIC,
+        }
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.profile().name.fmt(f)
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Target::ALL
+            .into_iter()
+            .find(|target| target.profile().name == s)
+            .ok_or_else(|| {
+                let names: Vec<_> = Target::ALL.iter().map(|t| t.profile().name).collect();
+                format!(
+                    "unknown target `{s}`, expected one of: {}",
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+/// Memory-mapped state of the target runtime
+#[derive(Debug)]
+pub struct TargetProfile {
+    pub name: &'static str,
+    /// One line summary for the help
+    pub description: &'static str,
+    /// Registers reinitialized after decompressed data is moved into its place
+    pub registers: &'static [RegisterInit],
+    /// Named regions of the memory map, ordered by address
+    pub regions: &'static [MemoryRegion],
+    /// Memory initialized by the runtime, which the injected code must neither use nor zero
+    pub reserved: &'static [Range<i32>],
+}
+
+impl TargetProfile {
+    /// Names of the memory regions overlapping the `range`
+    pub fn region_names(&self, range: Range<i32>) -> impl Iterator<Item = &'static str> {
+        self.regions
+            .iter()
+            .filter(move |region| region.range.start < range.end && range.start < region.range.end)
+            .map(|region| region.name)
+    }
+}
+
+/// Region of the runtime's memory map
+#[derive(Debug)]
+pub struct MemoryRegion {
+    pub name: &'static str,
+    pub range: Range<i32>,
+}
+
+/// Initial value of a memory-mapped register
+#[derive(Debug, Clone, Copy)]
+pub struct RegisterInit {
+    pub offset: i32,
+    pub value: RegisterValue,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum RegisterValue {
+    I16(i16),
+    I32(i32),
+    I64(i64),
+}
+
+impl RegisterValue {
+    /// Size of the value in bytes
+    pub fn width(self) -> i32 {
+        match self {
+            RegisterValue::I16(_) => 2,
+            RegisterValue::I32(_) => 4,
+            RegisterValue::I64(_) => 8,
+        }
+    }
+}
+
+pub const WASM4: TargetProfile = TargetProfile {
+    name: "wasm4",
+    description: "WASM-4 fantasy console, its palette, draw colors and mouse registers are reset",
+    registers: &[
+        RegisterInit {
+            offset: PALETTE_OFFSET,
+            value: RegisterValue::I64(PALETTE_DEFAULT[0]),
+        },
+        RegisterInit {
+            offset: PALETTE_OFFSET + 8,
+            value: RegisterValue::I64(PALETTE_DEFAULT[1]),
+        },
+        RegisterInit {
+            offset: DRAW_COLORS_OFFSET,
+            value: RegisterValue::I16(DRAW_COLORS_DEFAULT),
+        },
+        RegisterInit {
+            offset: MOUSE_XY_OFFSET,
+            value: RegisterValue::I32(MOUSE_XY_DEFAULT),
+        },
+    ],
+    regions: &[
+        MemoryRegion {
+            name: "reserved",
+            range: 0..PALETTE_OFFSET,
+        },
+        MemoryRegion {
+            name: "PALETTE",
+            range: PALETTE_OFFSET..DRAW_COLORS_OFFSET,
+        },
+        MemoryRegion {
+            name: "DRAW_COLORS",
+            range: DRAW_COLORS_OFFSET..0x16,
+        },
+        MemoryRegion {
+            name: "GAMEPADS",
+            range: 0x16..MOUSE_XY_OFFSET,
+        },
+        MemoryRegion {
+            name: "MOUSE",
+            range: MOUSE_XY_OFFSET..0x1f,
+        },
+        MemoryRegion {
+            name: "SYSTEM_FLAGS",
+            range: 0x1f..0x20,
+        },
+        MemoryRegion {
+            name: "NETPLAY",
+            range: 0x20..0x21,
+        },
+        MemoryRegion {
+            name: "reserved",
+            range: 0x21..0xa0,
+        },
+        MemoryRegion {
+            name: "FRAMEBUFFER",
+            range: 0xa0..0x19a0,
+        },
+        MemoryRegion {
+            name: "user memory",
+            range: 0x19a0..0x10000,
+        },
+    ],
+    reserved: &[],
+};
+
+/// MicroW8 initializes its palette and font once before the start, so they are left as they
+/// are, while time and input registers in the low memory are updated before every frame.
+pub const MICROW8: TargetProfile = TargetProfile {
+    name: "microw8",
+    description: "MicroW8 fantasy console, its palette and font are kept intact",
+    registers: &[],
+    regions: &[
+        MemoryRegion {
+            name: "PALETTE",
+            range: MICROW8_PALETTE,
+        },
+        MemoryRegion {
+            name: "FONT",
+            range: MICROW8_FONT,
+        },
+    ],
+    reserved: &[MICROW8_PALETTE, MICROW8_FONT],
+};
+
+pub const GENERIC: TargetProfile = TargetProfile {
+    name: "generic",
+    description: "Any runtime, only data is decompressed and the rest of memory is zeroed",
+    registers: &[],
+    regions: &[],
+    reserved: &[],
+};
@@ -0,0 +1,381 @@
+//! Reversing squeezing by decompressing data and stripping the injected code
+
+use anyhow::Context;
+use wasm_encoder::{
+    self as we,
+    reencode::{self, Reencode},
+};
+use wasmparser as wp;
+
+use crate::{
+    eval_i32, parse_passive_data, SqueezeError, Target, UnpackerComponents, UNPACKER_WASM,
+    WASM_FEATURES,
+};
+
+/// Decompress data of a module squeezed for the `target` and strip the unpacker and the
+/// prologue, reconstructing a module equivalent to the original one.
+///
+/// Only modules with the unpacker injected and data at constant addresses are supported.
+pub fn unsqueeze(input: &[u8], target: Target) -> anyhow::Result<Vec<u8>> {
+    let squeezed = SqueezedModule::parse(input).context("parsing input as wasm module")?;
+    let unpacker = UnpackerComponents::parse(UNPACKER_WASM)?;
+
+    let (start_fn, operators) = squeezed.start_operators()?;
+    let prologue = Prologue::parse(&operators, target)?;
+
+    let old_function_count = prologue
+        .unpack_fn
+        .checked_sub(squeezed.import_function_count + unpacker.unpack_fn_idx)
+        .context("unpack function is imported, which is not supported")?;
+    let unpacker_end = old_function_count + unpacker.functions.count();
+    let start_injected = start_fn == squeezed.import_function_count + unpacker_end;
+    anyhow::ensure!(
+        squeezed.function_bodies.len() as u32 == unpacker_end + u32::from(start_injected),
+        "unexpected functions follow the unpacker"
+    );
+    let old_type_count = squeezed
+        .type_count
+        .checked_sub(unpacker.types.count() + 1)
+        .context("module lacks the unpacker's types")?;
+
+    let (compressed_offset, compressed) = squeezed
+        .compressed
+        .context("module has no active data segment with compressed data")?;
+    let mut clusters = Vec::new();
+    for &(staging, address) in &prologue.unpacks {
+        let packed = address
+            .checked_sub(compressed_offset)
+            .and_then(|start| compressed.get(usize::try_from(start).ok()?..))
+            .context("unpack call refers to data outside of the compressed segment")?;
+        let mut data = upkr::unpack(packed, &upkr::Config::default(), i32::MAX as usize)
+            .context("decompressing data")?;
+        // Data is either copied out of the staging area or decompressed right into its place
+        let destination = match prologue.copies.iter().find(|copy| copy[1] == staging) {
+            Some(&[destination, _, len]) => {
+                data.truncate(len.try_into()?);
+                destination
+            }
+            None => staging,
+        };
+        log::debug!(
+            "Decompressed {} bytes into {} bytes at {destination:#x}",
+            packed.len(),
+            data.len()
+        );
+        clusters.push((destination, data));
+    }
+
+    let mut module = we::Module::new();
+    Unsqueezer {
+        old_type_count,
+        old_function_count,
+        start_fn: match start_injected {
+            true => None,
+            false => Some((start_fn - squeezed.import_function_count, prologue.len)),
+        },
+        function_bodies_seen: 0,
+        clusters,
+        data_emitted: false,
+    }
+    .parse_core_module(&mut module, wp::Parser::new(0), input)
+    .map_err(|err| match err {
+        reencode::Error::UserError(err) => anyhow::Error::from(err),
+        err => anyhow::Error::from(err),
+    })
+    .context("reencoding the module without the unpacker")?;
+    Ok(module.finish())
+}
+
+/// Whether the `input` module has been squeezed for the `target` already, which is told by the
+/// prologue calling the embedded unpacker or an imported unpack function
+pub(crate) fn is_squeezed(input: &[u8], target: Target) -> bool {
+    let detect = || -> anyhow::Result<bool> {
+        let squeezed = SqueezedModule::parse(input)?;
+        let (_, operators) = squeezed.start_operators()?;
+        let prologue = Prologue::parse(&operators, target)?;
+        let Some(unpack_idx) = prologue
+            .unpack_fn
+            .checked_sub(squeezed.import_function_count)
+        else {
+            return Ok(true);
+        };
+        let unpack_body = squeezed
+            .function_bodies
+            .get(unpack_idx as usize)
+            .context("unpack function does not exist")?;
+        let unpacker = UnpackerComponents::parse(UNPACKER_WASM)?;
+        let embedded_body = &unpacker.function_bodies[unpacker.unpack_fn_idx as usize];
+        // Indices differ after injection, so only the kinds of operators are compared
+        let fingerprint = |body: &wp::FunctionBody| -> anyhow::Result<Vec<_>> {
+            let mut reader = body.get_operators_reader()?;
+            let mut kinds = Vec::new();
+            while !reader.eof() {
+                kinds.push(std::mem::discriminant(&reader.read()?));
+            }
+            Ok(kinds)
+        };
+        Ok(fingerprint(unpack_body)? == fingerprint(embedded_body)?)
+    };
+    detect().unwrap_or(false)
+}
+
+/// Parts of a squeezed module needed to locate the injected code
+struct SqueezedModule<'a> {
+    import_function_count: u32,
+    type_count: u32,
+    start_fn: Option<u32>,
+    function_bodies: Vec<wp::FunctionBody<'a>>,
+    /// Offset and contents of the first active data segment
+    compressed: Option<(i32, &'a [u8])>,
+}
+
+impl<'a> SqueezedModule<'a> {
+    /// Index and operators of the start function
+    fn start_operators(&self) -> anyhow::Result<(u32, Vec<wp::Operator<'a>>)> {
+        let start_fn = self
+            .start_fn
+            .context("module has no start function, it does not seem to be squeezed")?;
+        let start_body = start_fn
+            .checked_sub(self.import_function_count)
+            .and_then(|idx| self.function_bodies.get(idx as usize))
+            .context("start function is imported, the module does not seem to be squeezed")?;
+        let mut operators = Vec::new();
+        let mut reader = start_body.get_operators_reader()?;
+        while !reader.eof() {
+            operators.push(reader.read()?);
+        }
+        Ok((start_fn, operators))
+    }
+
+    fn parse(input: &'a [u8]) -> anyhow::Result<Self> {
+        let mut module = SqueezedModule {
+            import_function_count: 0,
+            type_count: 0,
+            start_fn: None,
+            function_bodies: Vec::new(),
+            compressed: None,
+        };
+        let mut parser = wp::Parser::new(0);
+        parser.set_features(WASM_FEATURES);
+        for payload in parser.parse_all(input) {
+            match payload? {
+                wp::Payload::ImportSection(imports) => {
+                    for import in imports {
+                        if let wp::TypeRef::Func(_) = import?.ty {
+                            module.import_function_count += 1;
+                        }
+                    }
+                }
+                wp::Payload::TypeSection(types) => module.type_count += types.count(),
+                wp::Payload::StartSection { func, .. } => module.start_fn = Some(func),
+                wp::Payload::CodeSectionEntry(body) => module.function_bodies.push(body),
+                wp::Payload::DataSection(segments) => {
+                    for segment in segments {
+                        let segment = segment?;
+                        if let wp::DataKind::Active { offset_expr, .. } = segment.kind {
+                            if module.compressed.is_none() {
+                                let offset = eval_i32(&offset_expr, &[])
+                                    .context("evaluating compressed data offset")?;
+                                module.compressed = Some((offset, segment.data));
+                            }
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+        Ok(module)
+    }
+}
+
+/// Injected prologue at the beginning of the start function
+struct Prologue {
+    unpack_fn: u32,
+    /// Staging and compressed data addresses of every unpack call
+    unpacks: Vec<(i32, i32)>,
+    /// Destination, source and length of every copy
+    copies: Vec<[i32; 3]>,
+    /// Number of the prologue's operators
+    len: usize,
+}
+
+impl Prologue {
+    fn parse(operators: &[wp::Operator<'_>], target: Target) -> anyhow::Result<Self> {
+        use wp::Operator as O;
+
+        let mut unpack_fn = None;
+        let mut unpacks = Vec::new();
+        let mut rest = operators;
+        while let Some((
+            [_, staging, compressed],
+            [O::Call { function_index }, O::Drop, tail @ ..],
+        )) = i32_consts(rest)
+        {
+            anyhow::ensure!(
+                *unpack_fn.get_or_insert(*function_index) == *function_index,
+                "prologue calls different unpack functions"
+            );
+            unpacks.push((staging, compressed));
+            rest = tail;
+        }
+        let unpack_fn = unpack_fn.context(
+            "start function does not begin with an unpack call, the module does not seem to be \
+             squeezed",
+        )?;
+
+        if let Some(([_], [_load, O::GlobalSet { .. }, ..])) = i32_consts(rest) {
+            anyhow::bail!("restoring compressed global values is not supported");
+        }
+        if let [O::GlobalGet { .. }, ..] = rest {
+            anyhow::bail!("data relative to a base global is not supported");
+        }
+
+        let mut copies = Vec::new();
+        while let Some((copy, [O::MemoryCopy { .. }, tail @ ..])) = i32_consts(rest) {
+            copies.push(copy);
+            rest = tail;
+        }
+        while let Some(([_, 0, _], [O::MemoryFill { .. }, tail @ ..])) = i32_consts(rest) {
+            rest = tail;
+        }
+        for register in target.profile().registers {
+            match i32_consts(rest) {
+                Some(([offset], [_value, store, tail @ ..]))
+                    if offset == register.offset
+                        && matches!(
+                            store,
+                            O::I32Store16 { .. } | O::I32Store { .. } | O::I64Store { .. }
+                        ) =>
+                {
+                    rest = tail
+                }
+                _ => anyhow::bail!("prologue does not restore registers of the {target} target"),
+            }
+        }
+
+        Ok(Prologue {
+            unpack_fn,
+            unpacks,
+            copies,
+            len: operators.len() - rest.len(),
+        })
+    }
+}
+
+/// Values of `N` leading `i32.const` operators and the operators following them
+fn i32_consts<'a, 'b, const N: usize>(
+    operators: &'a [wp::Operator<'b>],
+) -> Option<([i32; N], &'a [wp::Operator<'b>])> {
+    let mut values = [0; N];
+    for (value, operator) in values.iter_mut().zip(operators) {
+        match operator {
+            wp::Operator::I32Const { value: constant } => *value = *constant,
+            _ => return None,
+        }
+    }
+    Some((values, operators.get(N..)?))
+}
+
+struct Unsqueezer {
+    old_type_count: u32,
+    old_function_count: u32,
+    /// Index of the original start function in the code section and its prologue length, or
+    /// `None` if the start function was injected
+    start_fn: Option<(u32, usize)>,
+    function_bodies_seen: u32,
+    /// Destination and decompressed data of every cluster
+    clusters: Vec<(i32, Vec<u8>)>,
+    data_emitted: bool,
+}
+
+impl Reencode for Unsqueezer {
+    type Error = SqueezeError;
+
+    fn parse_type_section(
+        &mut self,
+        types: &mut we::TypeSection,
+        section: wp::TypeSectionReader<'_>,
+    ) -> Result<(), reencode::Error<Self::Error>> {
+        // Unpacker's types and the injected one come last
+        for rec_group in section {
+            let rec_group = rec_group?;
+            if types.len() + rec_group.types().len() as u32 > self.old_type_count {
+                break;
+            }
+            self.parse_recursive_type_group(types.ty(), rec_group)?;
+        }
+        Ok(())
+    }
+
+    fn parse_function_section(
+        &mut self,
+        functions: &mut we::FunctionSection,
+        section: wp::FunctionSectionReader<'_>,
+    ) -> Result<(), reencode::Error<Self::Error>> {
+        for ty in section.into_iter().take(self.old_function_count as usize) {
+            functions.function(self.type_index(ty?));
+        }
+        Ok(())
+    }
+
+    fn parse_function_body(
+        &mut self,
+        code: &mut we::CodeSection,
+        func: wp::FunctionBody<'_>,
+    ) -> Result<(), reencode::Error<Self::Error>> {
+        let code_idx = self.function_bodies_seen;
+        self.function_bodies_seen += 1;
+        match self.start_fn {
+            _ if code_idx >= self.old_function_count => (),
+            Some((start_idx, prologue_len)) if start_idx == code_idx => {
+                let mut f = self.new_function_with_parsed_locals(&func)?;
+                let mut reader = func.get_operators_reader()?;
+                for _ in 0..prologue_len {
+                    reader.read()?;
+                }
+                while !reader.eof() {
+                    self.parse_instruction(&mut f, &mut reader)?;
+                }
+                code.function(&f);
+            }
+            _ => reencode::utils::parse_function_body(self, code, func)?,
+        }
+        Ok(())
+    }
+
+    fn parse_data_section(
+        &mut self,
+        data: &mut we::DataSection,
+        section: wp::DataSectionReader<'_>,
+    ) -> Result<(), reencode::Error<Self::Error>> {
+        if !std::mem::replace(&mut self.data_emitted, true) {
+            for (destination, bytes) in &self.clusters {
+                let offset = we::ConstExpr::i32_const(*destination);
+                data.active(0, &offset, bytes.iter().copied());
+            }
+        }
+        parse_passive_data(data, section)
+    }
+
+    fn data_index(&mut self, data: u32) -> u32 {
+        // Compressed segment comes first and is replaced by a segment per cluster
+        data - 1 + self.clusters.len() as u32
+    }
+
+    fn parse_core_module_section(
+        &mut self,
+        module: &mut we::Module,
+        section: wp::Payload<'_>,
+    ) -> Result<(), reencode::Error<Self::Error>> {
+        match section {
+            wp::Payload::StartSection { .. } if self.start_fn.is_none() => Ok(()),
+            wp::Payload::DataCountSection { count, .. } => {
+                module.section(&we::DataCountSection {
+                    count: count - 1 + self.clusters.len() as u32,
+                });
+                Ok(())
+            }
+            section => reencode::utils::parse_core_module_section(self, module, section),
+        }
+    }
+}
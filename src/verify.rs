@@ -0,0 +1,76 @@
+//! Running start functions of the original and the squeezed modules under an interpreter to
+//! compare the memory they leave
+
+use anyhow::Context;
+
+/// Instantiate both modules with stub imports and fail unless the squeezed one's memory ends up
+/// the same as the original one's
+pub fn verify_exec(original: &[u8], squeezed: &[u8]) -> anyhow::Result<()> {
+    let expected = memory_image(original).context("running the original module")?;
+    let actual = memory_image(squeezed).context("running the squeezed module")?;
+    anyhow::ensure!(
+        expected.len() == actual.len(),
+        "squeezed module's memory has {} bytes instead of {}",
+        actual.len(),
+        expected.len()
+    );
+    if let Some(offset) = std::iter::zip(&expected, &actual).position(|(e, a)| e != a) {
+        anyhow::bail!("squeezed module's memory differs from the original at {offset:#x}");
+    }
+    Ok(())
+}
+
+/// Memory of the `module` after its instantiation, including the start function
+fn memory_image(module: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut config = wasmi::Config::default();
+    config.consume_fuel(true);
+    let engine = wasmi::Engine::new(&config);
+    let module = wasmi::Module::new(&engine, module).context("loading the module")?;
+    let mut store = wasmi::Store::new(&engine, ());
+    // Bounds start functions which never return
+    store.set_fuel(u32::MAX.into())?;
+    let mut linker = wasmi::Linker::new(&engine);
+    let mut memory = None;
+    for import in module.imports() {
+        // Stubs return zeros, which is what the original module gets as well
+        let stub: wasmi::Extern = match import.ty() {
+            wasmi::ExternType::Func(ty) => {
+                let results = ty.results().to_vec();
+                wasmi::Func::new(&mut store, ty.clone(), move |_, _, out| {
+                    for (out, ty) in out.iter_mut().zip(&results) {
+                        *out = wasmi::Val::default(*ty);
+                    }
+                    Ok(())
+                })
+                .into()
+            }
+            wasmi::ExternType::Global(ty) => wasmi::Global::new(
+                &mut store,
+                wasmi::Val::default(ty.content()),
+                ty.mutability(),
+            )
+            .into(),
+            wasmi::ExternType::Table(ty) => {
+                wasmi::Table::new(&mut store, *ty, wasmi::Val::default(ty.element()))?.into()
+            }
+            wasmi::ExternType::Memory(ty) => {
+                let imported = wasmi::Memory::new(&mut store, *ty)?;
+                memory = Some(imported);
+                imported.into()
+            }
+        };
+        linker.define(import.module(), import.name(), stub)?;
+    }
+    let instance = linker
+        .instantiate(&mut store, &module)?
+        .start(&mut store)
+        .context("running the start function")?;
+    let memory = match memory {
+        Some(memory) => memory,
+        None => instance
+            .exports(&store)
+            .find_map(|export| export.into_memory())
+            .context("memory is neither imported nor exported")?,
+    };
+    Ok(memory.data(&store).to_vec())
+}
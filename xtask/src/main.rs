@@ -20,13 +20,45 @@ enum Commands {
         #[arg(env = "WASI_SDK_PATH")]
         wasi_sdk: PathBuf,
     },
+    /// Rebuild every embedded artifact, check that it matches the committed one bit for bit and
+    /// run the tests against it
+    RegenAll {
+        #[arg(env = "WASI_SDK_PATH")]
+        wasi_sdk: PathBuf,
+    },
+    /// Check that reencoding without compression and injection preserves modules
+    Roundtrip {
+        /// Directory with wasm modules
+        corpus: PathBuf,
+    },
+    /// Build example carts with the toolchains present and check that squeezing them works
+    TestExamples {
+        #[arg(env = "WASI_SDK_PATH")]
+        wasi_sdk: Option<PathBuf>,
+    },
+    /// Squeeze modules generated by wasm-smith, checking that outputs validate and leave the
+    /// same memory after instantiation. Failing modules are saved into `target/fuzz`.
+    Fuzz {
+        #[arg(long, default_value_t = 1000)]
+        iterations: u64,
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+    },
 }
 
 fn main() -> process::ExitCode {
-    let Args {
-        command: Commands::BuildUnpacker { wasi_sdk },
-    } = Args::parse();
+    match Args::parse().command {
+        Commands::BuildUnpacker { wasi_sdk } => {
+            build_unpacker(wasi_sdk, &workspace_root().join("src/upkr_unpacker.wasm"))
+        }
+        Commands::RegenAll { wasi_sdk } => regen_all(wasi_sdk),
+        Commands::Roundtrip { corpus } => roundtrip(&corpus),
+        Commands::TestExamples { wasi_sdk } => test_examples(wasi_sdk),
+        Commands::Fuzz { iterations, seed } => fuzz(iterations, seed),
+    }
+}
 
+fn workspace_root() -> PathBuf {
     let cargo = std::env::var_os("CARGO");
     let cargo = cargo.as_deref().unwrap_or("cargo".as_ref());
     let locate_project = process::Command::new(cargo)
@@ -41,10 +73,14 @@ fn main() -> process::ExitCode {
     );
     let workspace_manifest = String::from_utf8(locate_project.stdout).unwrap();
     let workspace_manifest = Path::new(workspace_manifest.trim());
-    let workspace_root = workspace_manifest.parent().unwrap();
+    workspace_manifest.parent().unwrap().to_owned()
+}
+
+/// Build the embedded unpacker into the `output_wasm` file
+fn build_unpacker(wasi_sdk: PathBuf, output_wasm: &Path) -> process::ExitCode {
+    let workspace_root = workspace_root();
 
     let source_file = workspace_root.join("src/upkr_unpacker.c");
-    let output_wasm = workspace_root.join("src/upkr_unpacker.wasm");
     let clang = wasi_sdk.join("bin/clang");
     let sysroot = wasi_sdk.join("share/wasi-sysroot");
 
@@ -89,7 +125,7 @@ fn main() -> process::ExitCode {
 
     // Stripping out unneeded stuff
 
-    let mut module = walrus::Module::from_file(&output_wasm).unwrap();
+    let mut module = walrus::Module::from_file(output_wasm).unwrap();
     module.start = None;
     let unused_exports: Vec<_> = module
         .exports
@@ -128,3 +164,408 @@ fn main() -> process::ExitCode {
 
     process::ExitCode::SUCCESS
 }
+
+/// Rebuild embedded artifacts into `target/regen`, check that they match the committed ones and
+/// that the unpacker's interface and features are what the library assumes, then run the tests
+/// against them. Tests assemble their modules from the text format, so there are no fixtures.
+fn regen_all(wasi_sdk: PathBuf) -> process::ExitCode {
+    let workspace_root = workspace_root();
+    let regen_dir = workspace_root.join("target/regen");
+    std::fs::create_dir_all(&regen_dir).unwrap();
+
+    let unpacker_wasm = workspace_root.join("src/upkr_unpacker.wasm");
+    let rebuilt_wasm = regen_dir.join("upkr_unpacker.wasm");
+    let status = build_unpacker(wasi_sdk, &rebuilt_wasm);
+    if status != process::ExitCode::SUCCESS {
+        return status;
+    }
+    let committed = std::fs::read(&unpacker_wasm).unwrap();
+    let rebuilt = std::fs::read(&rebuilt_wasm).unwrap();
+
+    let mut failures = 0;
+    if committed == rebuilt {
+        eprintln!("ok   {}", unpacker_wasm.display());
+    } else {
+        eprintln!(
+            "FAIL {}: rebuilt {} bytes in {} differ from committed {} bytes",
+            unpacker_wasm.display(),
+            rebuilt.len(),
+            rebuilt_wasm.display(),
+            committed.len()
+        );
+        failures += 1;
+    }
+    match check_unpacker_metadata(&rebuilt) {
+        Ok(()) => eprintln!("ok   unpacker interface and features"),
+        Err(err) => {
+            eprintln!("FAIL unpacker interface and features: {err}");
+            failures += 1;
+        }
+    }
+
+    let cargo = env::var_os("CARGO");
+    let cargo = cargo.as_deref().unwrap_or("cargo".as_ref());
+    let test_status = process::Command::new(cargo)
+        .args(["test", "--package", "wasm-squeeze", "--all-features"])
+        .status()
+        .unwrap();
+    if test_status.success() {
+        eprintln!("ok   tests");
+    } else {
+        eprintln!("FAIL tests: {test_status:?}");
+        failures += 1;
+    }
+
+    if failures == 0 {
+        process::ExitCode::SUCCESS
+    } else {
+        eprintln!("{failures} check(s) failed");
+        process::ExitCode::FAILURE
+    }
+}
+
+/// Check that the `unpacker` only imports the memory, only exports the unpack function and
+/// needs no optional features besides bulk memory, which the prologue requires anyway
+fn check_unpacker_metadata(unpacker: &[u8]) -> Result<(), String> {
+    for payload in wasmparser::Parser::new(0).parse_all(unpacker) {
+        match payload.map_err(|err| err.to_string())? {
+            wasmparser::Payload::ImportSection(imports) => {
+                for import in imports {
+                    let import = import.map_err(|err| err.to_string())?;
+                    if (import.module, import.name) != ("env", "memory") {
+                        return Err(format!("imports {}.{}", import.module, import.name));
+                    }
+                }
+            }
+            wasmparser::Payload::ExportSection(exports) => {
+                for export in exports {
+                    let export = export.map_err(|err| err.to_string())?;
+                    if export.name != "upkr_unpack" {
+                        return Err(format!("exports `{}`", export.name));
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    for &(name, feature) in wasm_squeeze::OPTIONAL_FEATURES {
+        if feature == wasmparser::WasmFeatures::BULK_MEMORY {
+            continue;
+        }
+        let features = wasm_squeeze::WASM_FEATURES.difference(feature);
+        wasmparser::Validator::new_with_features(features)
+            .validate_all(unpacker)
+            .map_err(|err| format!("requires {name}: {err}"))?;
+    }
+    Ok(())
+}
+
+/// Build `wasm-squeeze` in release mode and return the path to its binary
+fn build_wasm_squeeze(workspace_root: &Path) -> PathBuf {
+    let cargo = env::var_os("CARGO");
+    let cargo = cargo.as_deref().unwrap_or("cargo".as_ref());
+    let build_status = process::Command::new(cargo)
+        .args(["build", "--release", "--package", "wasm-squeeze"])
+        .status()
+        .unwrap();
+    assert!(build_status.success(), "Building `wasm-squeeze` has failed");
+    workspace_root.join("target/release/wasm-squeeze")
+}
+
+fn roundtrip(corpus: &Path) -> process::ExitCode {
+    let wasm_squeeze = build_wasm_squeeze(&workspace_root());
+
+    let mut failures = 0;
+    for entry in std::fs::read_dir(corpus).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension() != Some("wasm".as_ref()) {
+            continue;
+        }
+        let input = std::fs::read(&path).unwrap();
+        let output = process::Command::new(&wasm_squeeze)
+            .args(["--no-compress", "--no-inject"])
+            .arg(&path)
+            .args(["-o", "-"])
+            .stderr(process::Stdio::inherit())
+            .output()
+            .unwrap();
+        if let Err(err) = check_roundtrip(&input, &output) {
+            eprintln!("FAIL {}: {err}", path.display());
+            failures += 1;
+        } else {
+            eprintln!("ok   {}", path.display());
+        }
+    }
+
+    if failures == 0 {
+        process::ExitCode::SUCCESS
+    } else {
+        eprintln!("{failures} module(s) did not roundtrip");
+        process::ExitCode::FAILURE
+    }
+}
+
+fn test_examples(wasi_sdk: Option<PathBuf>) -> process::ExitCode {
+    let workspace_root = workspace_root();
+    let wasm_squeeze = build_wasm_squeeze(&workspace_root);
+    let examples = workspace_root.join("examples");
+    let output_dir = workspace_root.join("target/examples");
+    std::fs::create_dir_all(&output_dir).unwrap();
+
+    let mut failures = 0;
+    for (name, cart, build) in example_builds(&examples, &output_dir, wasi_sdk.as_deref()) {
+        let Some(mut build) = build else {
+            eprintln!("skip {name}: toolchain is not configured");
+            continue;
+        };
+        let status = match build.status() {
+            Ok(status) => status,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                eprintln!("skip {name}: toolchain is not installed");
+                continue;
+            }
+            Err(err) => panic!("running the build of {name}: {err}"),
+        };
+        let result = if status.success() {
+            squeeze_example(
+                &wasm_squeeze,
+                &cart,
+                &output_dir.join(format!("{name}.squeezed.wasm")),
+            )
+        } else {
+            Err(format!("build failed with status: {status:?}"))
+        };
+        match result {
+            Ok(note) => eprintln!("ok   {name}{note}"),
+            Err(err) => {
+                eprintln!("FAIL {name}: {err}");
+                failures += 1;
+            }
+        }
+    }
+
+    if failures == 0 {
+        process::ExitCode::SUCCESS
+    } else {
+        eprintln!("{failures} example(s) failed");
+        process::ExitCode::FAILURE
+    }
+}
+
+fn fuzz(iterations: u64, seed: u64) -> process::ExitCode {
+    let output_dir = workspace_root().join("target/fuzz");
+    std::fs::create_dir_all(&output_dir).unwrap();
+    // Carts have a single memory with data and no start function for the memory to be compared
+    // right after instantiation
+    let config = wasm_smith::Config {
+        min_memories: 1,
+        max_memories: 1,
+        min_data_segments: 1,
+        max_imports: 0,
+        export_everything: true,
+        allow_start_export: false,
+        disallow_traps: true,
+        ..Default::default()
+    };
+
+    let mut rng = seed ^ 0x9e37_79b9_7f4a_7c15;
+    let mut bytes = vec![0; 0x4000];
+    let (mut squeezed, mut failures) = (0, 0);
+    for iteration in 0..iterations {
+        for chunk in bytes.chunks_mut(8) {
+            // xorshift64
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            chunk.copy_from_slice(&rng.to_le_bytes()[..chunk.len()]);
+        }
+        let mut unstructured = arbitrary::Unstructured::new(&bytes);
+        let Ok(module) = wasm_smith::Module::new(config.clone(), &mut unstructured) else {
+            continue;
+        };
+        let module = module.to_bytes();
+        match fuzz_module(&module) {
+            Ok(true) => squeezed += 1,
+            Ok(false) => (),
+            Err(err) => {
+                let path = output_dir.join(format!("{seed}-{iteration}.wasm"));
+                std::fs::write(&path, &module).unwrap();
+                eprintln!("FAIL {}: {err}", path.display());
+                failures += 1;
+            }
+        }
+    }
+
+    eprintln!("Squeezed {squeezed} out of {iterations} generated module(s)");
+    if failures == 0 {
+        process::ExitCode::SUCCESS
+    } else {
+        eprintln!("{failures} module(s) failed");
+        process::ExitCode::FAILURE
+    }
+}
+
+/// Squeeze the `module` and check the output, returning whether it was squeezed. Unsupported
+/// modules are fine, internal errors are not.
+fn fuzz_module(module: &[u8]) -> Result<bool, String> {
+    let options = wasm_squeeze::SqueezeOptions::default();
+    let outcome = match wasm_squeeze::squeeze_reader(module, &options) {
+        Ok(outcome) => outcome,
+        Err(err)
+            if err
+                .chain()
+                .any(|cause| cause.is::<wasm_squeeze::SqueezeError>()) =>
+        {
+            return Err(format!("{err:#}"))
+        }
+        Err(_) => return Ok(false),
+    };
+    if outcome.passthrough_reason.is_some() {
+        return match outcome.output == module {
+            true => Ok(false),
+            false => Err("passed through output differs from the input".to_owned()),
+        };
+    }
+    wasm_squeeze::validate_output(&outcome.output).map_err(|err| format!("{err:#}"))?;
+    wasm_squeeze::verify::verify_exec(module, &outcome.output).map_err(|err| format!("{err:#}"))?;
+    Ok(true)
+}
+
+/// Name, cart path and build command of every example building into the `output_dir`, with no
+/// command for those needing toolchains which are not configured
+fn example_builds(
+    examples: &Path,
+    output_dir: &Path,
+    wasi_sdk: Option<&Path>,
+) -> Vec<(&'static str, PathBuf, Option<process::Command>)> {
+    let cart = |name| output_dir.join(format!("{name}.wasm"));
+
+    let mut rust = process::Command::new("cargo");
+    rust.current_dir(examples.join("rust"))
+        .args(["build", "--release", "--target-dir"])
+        .arg(output_dir.join("rust"));
+
+    let c = wasi_sdk.map(|wasi_sdk| {
+        let mut c = process::Command::new(wasi_sdk.join("bin/clang"));
+        c.arg("--sysroot")
+            .arg(wasi_sdk.join("share/wasi-sysroot"))
+            .args([
+                "-Oz",
+                "-nostdlib",
+                "-Wl,--no-entry,--import-memory,--strip-all",
+                "-Wl,--initial-memory=65536,--max-memory=65536,-zstack-size=14752",
+            ])
+            .arg(examples.join("c/main.c"))
+            .arg("-o")
+            .arg(cart("c"));
+        c
+    });
+
+    let mut zig = process::Command::new("zig");
+    zig.args([
+        "build-exe",
+        "-target",
+        "wasm32-freestanding",
+        "-O",
+        "ReleaseSmall",
+        "-fno-entry",
+        "--export=update",
+        "--import-memory",
+        "--initial-memory=65536",
+        "--max-memory=65536",
+        "--stack",
+        "14752",
+    ])
+    .arg(examples.join("zig/main.zig"))
+    .arg(format!("-femit-bin={}", cart("zig").display()));
+
+    let mut assemblyscript = process::Command::new("npx");
+    assemblyscript
+        .args(["--yes", "--package", "assemblyscript", "asc"])
+        .arg(examples.join("assemblyscript/main.ts"))
+        .args([
+            "--runtime",
+            "stub",
+            "--optimize",
+            "--shrinkLevel",
+            "2",
+            "--noAssert",
+            "--use",
+            "abort=",
+            "--importMemory",
+            "--initialMemory",
+            "1",
+            "--maximumMemory",
+            "1",
+            "--memoryBase",
+            "6560",
+            "--outFile",
+        ])
+        .arg(cart("assemblyscript"));
+
+    vec![
+        (
+            "rust",
+            output_dir.join("rust/wasm32-unknown-unknown/release/rust_cart.wasm"),
+            Some(rust),
+        ),
+        ("c", cart("c"), c),
+        ("zig", cart("zig"), Some(zig)),
+        (
+            "assemblyscript",
+            cart("assemblyscript"),
+            Some(assemblyscript),
+        ),
+    ]
+}
+
+/// Squeeze the `cart` and validate the output, returning a note on the outcome
+fn squeeze_example(wasm_squeeze: &Path, cart: &Path, output: &Path) -> Result<String, String> {
+    let status = process::Command::new(wasm_squeeze)
+        .arg(cart)
+        .arg("-o")
+        .arg(output)
+        .stderr(process::Stdio::inherit())
+        .status()
+        .unwrap();
+    let note = match status.code() {
+        Some(0) => String::new(),
+        // Tiny carts might not benefit from squeezing
+        Some(2) => " (passed through)".to_owned(),
+        _ => return Err(format!("`wasm-squeeze` failed with status: {status:?}")),
+    };
+    let output = std::fs::read(output).map_err(|err| err.to_string())?;
+    wasmparser::Validator::new_with_features(wasmparser::WasmFeatures::all())
+        .validate_all(&output)
+        .map_err(|err| format!("invalid output: {err}"))?;
+    // Passing through must write the input byte for byte
+    if status.code() == Some(2) && output != std::fs::read(cart).map_err(|err| err.to_string())? {
+        return Err("passed through output differs from the input".to_owned());
+    }
+    Ok(note)
+}
+
+fn check_roundtrip(input: &[u8], output: &process::Output) -> Result<(), String> {
+    if !output.status.success() {
+        return Err(format!(
+            "`wasm-squeeze` failed with status: {:?}",
+            output.status
+        ));
+    }
+    let output = &output.stdout;
+    wasmparser::Validator::new_with_features(wasmparser::WasmFeatures::all())
+        .validate_all(output)
+        .map_err(|err| format!("invalid output: {err}"))?;
+    if input == output.as_slice() {
+        return Ok(());
+    }
+    let input = wasmprinter::print_bytes(input).map_err(|err| err.to_string())?;
+    let output = wasmprinter::print_bytes(output).map_err(|err| err.to_string())?;
+    match input.lines().zip(output.lines()).position(|(i, o)| i != o) {
+        Some(line) => Err(format!("text differs at line {}", line + 1)),
+        None if input.lines().count() != output.lines().count() => {
+            Err("text differs in line count".to_owned())
+        }
+        None => Ok(()),
+    }
+}